@@ -0,0 +1,12 @@
+#!/bin/sh
+# Runs the test suite once for every global allocator feature and once with
+# the heap debugging wrapper around the default allocator.
+set -e
+
+for allocator in alloc-bump alloc-linked-list alloc-fixed-block alloc-slab; do
+    echo "Testing with $allocator"
+    cargo xtest --no-default-features --features "$allocator" "$@"
+done
+
+echo "Testing with heap-debug"
+cargo xtest --features heap-debug "$@"
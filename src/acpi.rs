@@ -0,0 +1,169 @@
+use crate::memory::physical_memory_offset;
+use alloc::vec::Vec;
+use core::ptr;
+
+/// The signature at the start of the root system description pointer.
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// The size of the header that every system description table starts with.
+const SDT_HEADER_SIZE: usize = 36;
+
+/// An I/O APIC as described by the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: u64,
+    /// The first global system interrupt that the I/O APIC handles.
+    pub gsi_base: u32,
+}
+
+/// A legacy ISA IRQ that is not connected to the global system interrupt with
+/// the same number, or that doesn't use the ISA polarity and trigger mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// The interrupt controller configuration from the ACPI MADT.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    /// The local APIC IDs of all enabled processors.
+    pub processors: Vec<u8>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    /// Returns the global system interrupt, polarity and trigger mode of the
+    /// given ISA IRQ.
+    pub fn isa_irq(&self, irq: u8) -> InterruptOverride {
+        self.overrides
+            .iter()
+            .find(|o| o.irq == irq)
+            .copied()
+            .unwrap_or(InterruptOverride {
+                irq,
+                gsi: u32::from(irq),
+                active_low: false,
+                level_triggered: false,
+            })
+    }
+}
+
+/// Finds and parses the MADT.
+///
+/// Returns `None` if there is no valid RSDP or no MADT. Needs `memory::init`,
+/// since the tables are read through the physical memory mapping.
+pub fn madt() -> Option<Madt> {
+    let table = find_table(b"APIC")?;
+    let length = unsafe { read::<u32>(table + 4) } as u64;
+
+    let mut madt = Madt {
+        local_apic_address: u64::from(unsafe { read::<u32>(table + SDT_HEADER_SIZE as u64) }),
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    let mut entry = table + SDT_HEADER_SIZE as u64 + 8;
+    while entry + 2 <= table + length {
+        let (entry_type, entry_length) = unsafe { (read::<u8>(entry), read::<u8>(entry + 1)) };
+        if entry_length < 2 {
+            // a malformed entry, stop instead of looping forever
+            break;
+        }
+        unsafe {
+            match entry_type {
+                0 => {
+                    let flags = read::<u32>(entry + 4);
+                    if flags & 1 != 0 {
+                        madt.processors.push(read::<u8>(entry + 3));
+                    }
+                }
+                1 => madt.io_apics.push(IoApicInfo {
+                    id: read::<u8>(entry + 2),
+                    address: u64::from(read::<u32>(entry + 4)),
+                    gsi_base: read::<u32>(entry + 8),
+                }),
+                2 => {
+                    let flags = read::<u16>(entry + 8);
+                    madt.overrides.push(InterruptOverride {
+                        irq: read::<u8>(entry + 3),
+                        gsi: read::<u32>(entry + 4),
+                        active_low: flags & 0b11 == 0b11,
+                        level_triggered: (flags >> 2) & 0b11 == 0b11,
+                    });
+                }
+                5 => madt.local_apic_address = read::<u64>(entry + 4),
+                _ => {}
+            }
+        }
+        entry += u64::from(entry_length);
+    }
+    Some(madt)
+}
+
+/// Returns the physical address of the system description table with the
+/// given signature.
+fn find_table(signature: &[u8; 4]) -> Option<u64> {
+    let rsdp = find_rsdp()?;
+    let revision = unsafe { read::<u8>(rsdp + 15) };
+    let (root, entry_size) = if revision >= 2 {
+        (unsafe { read::<u64>(rsdp + 24) }, 8)
+    } else {
+        (u64::from(unsafe { read::<u32>(rsdp + 16) }), 4)
+    };
+    let length = unsafe { read::<u32>(root + 4) } as u64;
+    if !has_valid_checksum(root, length as usize) {
+        return None;
+    }
+
+    let entries = length.saturating_sub(SDT_HEADER_SIZE as u64) / entry_size;
+    (0..entries)
+        .map(|i| {
+            let entry = root + SDT_HEADER_SIZE as u64 + i * entry_size;
+            if entry_size == 8 {
+                unsafe { read::<u64>(entry) }
+            } else {
+                u64::from(unsafe { read::<u32>(entry) })
+            }
+        })
+        .find(|&table| {
+            unsafe { read::<[u8; 4]>(table) } == *signature
+                && has_valid_checksum(table, unsafe { read::<u32>(table + 4) } as usize)
+        })
+}
+
+/// Searches the first KiB of the extended BIOS data area and the BIOS area
+/// below 1 MiB for the root system description pointer.
+fn find_rsdp() -> Option<u64> {
+    let ebda = u64::from(unsafe { read::<u16>(0x40e) }) << 4;
+    let candidates = (ebda..ebda + 1024).step_by(16).chain((0xe0000..0x100000).step_by(16));
+    for addr in candidates {
+        if unsafe { read::<[u8; 8]>(addr) } == *RSDP_SIGNATURE && has_valid_checksum(addr, 20) {
+            return Some(addr);
+        }
+    }
+    None
+}
+
+/// Returns whether the bytes at the given physical address sum up to zero.
+fn has_valid_checksum(addr: u64, length: usize) -> bool {
+    let sum = (0..length as u64).fold(0u8, |sum, i| {
+        sum.wrapping_add(unsafe { read::<u8>(addr + i) })
+    });
+    sum == 0
+}
+
+/// Reads a `T` at the given physical address.
+///
+/// This function is unsafe because the caller must guarantee that the address
+/// is backed by memory.
+unsafe fn read<T: Copy>(phys_addr: u64) -> T {
+    let ptr: *const T = (physical_memory_offset() + phys_addr).as_ptr();
+    ptr::read_unaligned(ptr)
+}
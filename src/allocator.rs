@@ -1,46 +1,115 @@
+use crate::memory::{self, guard::{self, GuardPosition}, vmm};
+use crate::serial_println;
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
-// use bump::BumpAllocator;
-// use linked_list::LinkedListAllocator;
-use fixed_size_block::FixedSizeBlockAllocator;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Creates an allocator of the given type that manages a test heap of its own
+/// and returns a `&'static Locked` of it.
+#[cfg(test)]
+macro_rules! test_allocator {
+    ($allocator:ty) => {{
+        use $crate::allocator::{Locked, TestHeap, TEST_HEAP_SIZE};
+
+        static mut HEAP: TestHeap = TestHeap([0; TEST_HEAP_SIZE]);
+        static ALLOCATOR: Locked<$allocator> = Locked::new(<$allocator>::new());
+        // every expansion has its own heap, which only this allocator uses
+        unsafe { ALLOCATOR.lock().init(HEAP.0.as_mut_ptr() as usize, TEST_HEAP_SIZE) };
+        &ALLOCATOR
+    }};
+}
 
 pub mod bump;
+pub mod debug;
 pub mod linked_list;
 pub mod fixed_size_block;
+pub mod slab;
 
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, PageSize, PageTableFlags, Size2MiB, Size4KiB,
     },
     VirtAddr,
 };
 
+#[cfg(feature = "alloc-bump")]
+use bump::BumpAllocator as HeapAllocator;
+#[cfg(feature = "alloc-linked-list")]
+use linked_list::LinkedListAllocator as HeapAllocator;
+#[cfg(feature = "alloc-fixed-block")]
+use fixed_size_block::FixedSizeBlockAllocator as HeapAllocator;
+#[cfg(feature = "alloc-slab")]
+use slab::SlabAllocator as HeapAllocator;
+
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-fixed-block",
+    feature = "alloc-slab"
+)))]
+compile_error!(
+    "no global allocator selected, enable one of the `alloc-bump`, \
+     `alloc-linked-list`, `alloc-fixed-block` or `alloc-slab` features"
+);
+
+#[cfg(any(
+    all(feature = "alloc-bump", feature = "alloc-linked-list"),
+    all(feature = "alloc-bump", feature = "alloc-fixed-block"),
+    all(feature = "alloc-bump", feature = "alloc-slab"),
+    all(feature = "alloc-linked-list", feature = "alloc-fixed-block"),
+    all(feature = "alloc-linked-list", feature = "alloc-slab"),
+    all(feature = "alloc-fixed-block", feature = "alloc-slab")
+))]
+compile_error!(
+    "more than one global allocator selected, use `--no-default-features` \
+     when enabling `alloc-bump`, `alloc-linked-list` or `alloc-slab`"
+);
+
+#[cfg_attr(not(feature = "heap-debug"), global_allocator)]
+static ALLOCATOR: Locked<HeapAllocator> = Locked::new(HeapAllocator::new());
+
+/// Checks all heap allocations for corruption, see `debug::DebugAllocator`.
+#[cfg(feature = "heap-debug")]
 #[global_allocator]
-// static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+static DEBUG_ALLOCATOR: debug::DebugAllocator<Locked<HeapAllocator>> =
+    debug::DebugAllocator::new(&ALLOCATOR);
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024;
 
+/// The size up to which the heap can grow on demand.
+///
+/// Every allocator maps more pages at the end of the heap when an allocation
+/// doesn't fit into it anymore.
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+/// The minimum number of bytes that are mapped when the heap grows.
+const HEAP_GROWTH_STEP: usize = 64 * 1024;
+
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>
 ) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
+    // reserve the whole range the heap can grow into, including the guard pages
+    // directly below the heap and above its maximum size, which stay unmapped
+    let heap_start = VirtAddr::new(HEAP_START as u64);
+    let heap_ceiling = heap_start + HEAP_MAX_SIZE;
+    vmm::VMM
+        .lock()
+        .reserve(
+            "kernel heap",
+            heap_start - Size4KiB::SIZE,
+            HEAP_MAX_SIZE as u64 + 2 * Size4KiB::SIZE,
+        )
+        .expect("kernel heap range is in use");
 
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        mapper.map_to(page, frame, flags, frame_allocator)?.flush();
-    }
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    vmm::map_pages(heap_start, HEAP_SIZE as u64, flags, mapper, frame_allocator)?;
+
+    guard::register("kernel heap", GuardPosition::Below, heap_start - Size4KiB::SIZE, heap_start);
+    guard::register("kernel heap", GuardPosition::Above, heap_ceiling, heap_ceiling + Size4KiB::SIZE);
 
     unsafe {
         ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
@@ -49,6 +118,86 @@ pub fn init_heap(
     Ok(())
 }
 
+/// Sets the size up to which the heap may grow on demand.
+///
+/// The limit is capped at `HEAP_MAX_SIZE`. Lowering it below the current heap
+/// size only prevents further growth.
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit.min(HEAP_MAX_SIZE), Ordering::Relaxed);
+}
+
+/// Returns the size up to which the heap may grow on demand.
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Maps up to `size` additional bytes at `heap_end`, the current end of the
+/// kernel heap.
+///
+/// Uses `memory::MAPPER` and `memory::FRAME_ALLOCATOR`, so the heap can only grow
+/// after `memory::init_globals` was called. Returns the number of bytes that were
+/// mapped, which is a multiple of the page size and `0` if the heap can't grow.
+fn grow_heap(heap_end: usize, size: usize) -> usize {
+    let heap_limit_end = HEAP_START + heap_limit();
+    if heap_end < HEAP_START || heap_end >= heap_limit_end {
+        // not the kernel heap or already at the limit
+        return 0;
+    }
+    let page_size = Size4KiB::SIZE as usize;
+    let huge_page_size = Size2MiB::SIZE as usize;
+    let size = align_up(size.max(HEAP_GROWTH_STEP), page_size);
+    let size = size.min(heap_limit_end - heap_end) / page_size * page_size;
+
+    // don't wait for the locks: if they are held, we were called from code that
+    // is changing mappings itself and waiting would deadlock
+    let mut mapper = match memory::MAPPER.try_lock() {
+        Some(mapper) => mapper,
+        None => return 0,
+    };
+    let mut frame_allocator = match memory::FRAME_ALLOCATOR.try_lock() {
+        Some(frame_allocator) => frame_allocator,
+        None => return 0,
+    };
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return 0,
+    };
+
+    // map in steps so that a failure keeps the pages that were already mapped;
+    // aligned 2 MiB steps let `map_range` use a huge page
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let mut mapped = 0;
+    while mapped < size {
+        let addr = heap_end + mapped;
+        let step = if addr % huge_page_size == 0 && size - mapped >= huge_page_size {
+            huge_page_size
+        } else {
+            page_size
+        };
+        let start = VirtAddr::new(addr as u64);
+        if vmm::map_range(start, step as u64, flags, mapper, frame_allocator).is_err() {
+            break;
+        }
+        mapped += step;
+    }
+    mapped
+}
+
+/// Grows the given fallback heap so that it can hold an allocation with the
+/// given layout.
+///
+/// Only succeeds for the heap at `HEAP_START`. Returns whether the heap grew.
+fn grow_fallback_heap(heap: &mut linked_list_allocator::Heap, layout: Layout) -> bool {
+    let grown = grow_heap(heap.top(), layout.size() + layout.align());
+    if grown == 0 {
+        return false;
+    }
+    // the new pages directly follow the end of the heap
+    unsafe { heap.extend(grown) };
+    true
+}
+
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
 }
@@ -65,6 +214,113 @@ impl <A> Locked<A> {
     }
 }
 
+impl <A: HeapStatistics> Locked<A> {
+    /// Returns a snapshot of the allocator's statistics.
+    pub fn stats(&self) -> HeapStats {
+        self.lock().stats()
+    }
+
+    /// Prints the allocator's statistics over the serial interface.
+    pub fn dump_stats(&self) {
+        // take the snapshot first so that the lock isn't held while printing
+        let stats = self.stats();
+        stats.dump();
+    }
+}
+
+/// Returns a snapshot of the global allocator's statistics.
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
+
+/// Prints the global allocator's statistics over the serial interface.
+pub fn dump_heap_stats() {
+    ALLOCATOR.dump_stats()
+}
+
+/// Creates a named slab cache for objects of type `T` on the global allocator.
+///
+/// Returns `None` if `T` is too large for slabs or if all cache slots are in use.
+#[cfg(feature = "alloc-slab")]
+pub fn object_cache<T>(name: &'static str) -> Option<slab::ObjectCache<T>> {
+    slab::ObjectCache::new(&ALLOCATOR, name)
+}
+
+/// Prints the statistics of all slab caches of the global allocator.
+#[cfg(feature = "alloc-slab")]
+pub fn dump_slab_stats() {
+    ALLOCATOR.dump_cache_stats()
+}
+
+/// Allocators that can report how their heap is used.
+pub trait HeapStatistics {
+    /// Returns a snapshot of the allocator's statistics.
+    fn stats(&mut self) -> HeapStats;
+}
+
+/// A snapshot of the state of a heap allocator.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapStats {
+    /// The total size of the heap in bytes.
+    pub heap_size: usize,
+    /// The number of bytes that are currently handed out, including the
+    /// rounding to block sizes.
+    pub bytes_allocated: usize,
+    /// The number of bytes that are available for new allocations.
+    pub bytes_free: usize,
+    /// The number of successful allocations since the heap was initialized.
+    pub allocations: usize,
+    /// The number of deallocations since the heap was initialized.
+    pub deallocations: usize,
+    /// The size of the largest allocation that can currently succeed without
+    /// growing the heap.
+    ///
+    /// The fallback heap of the `FixedSizeBlockAllocator` and the
+    /// `SlabAllocator` doesn't expose its free regions, so for these it counts
+    /// all free bytes of the fallback heap and is only an upper bound.
+    pub largest_free_region: usize,
+    /// The block lists of the `FixedSizeBlockAllocator`; all zero for the other
+    /// allocators.
+    pub size_classes: [SizeClassStats; fixed_size_block::BLOCK_SIZES.len()],
+}
+
+/// Statistics about the blocks of a single size in a `FixedSizeBlockAllocator`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeClassStats {
+    pub block_size: usize,
+    /// The number of blocks of this size that are currently allocated.
+    pub allocated: usize,
+    /// The number of blocks of this size that are in the free list.
+    pub free: usize,
+}
+
+impl HeapStats {
+    /// Prints the statistics over the serial interface.
+    pub fn dump(&self) {
+        serial_println!(
+            "heap: {} bytes, {} allocated, {} free, largest free region {}",
+            self.heap_size,
+            self.bytes_allocated,
+            self.bytes_free,
+            self.largest_free_region
+        );
+        serial_println!(
+            "heap: {} allocations, {} deallocations, {} live",
+            self.allocations,
+            self.deallocations,
+            self.allocations - self.deallocations
+        );
+        for class in self.size_classes.iter().filter(|c| c.block_size != 0) {
+            serial_println!(
+                "heap: {:>4} byte blocks: {} allocated, {} free",
+                class.block_size,
+                class.allocated,
+                class.free
+            );
+        }
+    }
+}
+
 /// Align the given address `addr` upwards to alignment `align`.
 fn align_up(addr: usize, align: usize) -> usize {
     let remainder = addr % align;
@@ -75,6 +331,14 @@ fn align_up(addr: usize, align: usize) -> usize {
     }
 }
 
+/// The size of the heaps that the allocator unit tests run on.
+#[cfg(test)]
+const TEST_HEAP_SIZE: usize = 16 * 1024;
+
+#[cfg(test)]
+#[repr(align(4096))]
+struct TestHeap([u8; TEST_HEAP_SIZE]);
+
 pub struct Dummy;
 
 unsafe impl GlobalAlloc for Dummy {
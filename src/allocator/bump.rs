@@ -1,4 +1,4 @@
-use super::{align_up, Locked};
+use super::{align_up, HeapStatistics, HeapStats, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
@@ -6,7 +6,9 @@ pub struct BumpAllocator {
     heap_start: usize,
     heap_end: usize,
     next: usize,
-    allocations: usize
+    allocations: usize,
+    total_allocations: usize,
+    total_deallocations: usize,
 }
 
 impl BumpAllocator {
@@ -15,7 +17,9 @@ impl BumpAllocator {
             heap_start: 0,
             heap_end: 0,
             next: 0,
-            allocations: 0
+            allocations: 0,
+            total_allocations: 0,
+            total_deallocations: 0,
         }
     }
 
@@ -40,11 +44,18 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
             None => return ptr::null_mut()
         };
 
+        if alloc_end > bump.heap_end {
+            // only the kernel heap grows, for other heaps this maps nothing
+            let grown = super::grow_heap(bump.heap_end, alloc_end - bump.heap_end);
+            bump.heap_end += grown;
+        }
+
         if alloc_end > bump.heap_end {
             ptr::null_mut() // out of memory
         } else {
             bump.next = alloc_end;
             bump.allocations += 1;
+            bump.total_allocations += 1;
             alloc_start as *mut u8
         }
     }
@@ -53,8 +64,25 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         let mut bump = self.lock(); // get mutable reference
 
         bump.allocations -= 1;
+        bump.total_deallocations += 1;
         if bump.allocations == 0 {
             bump.next = bump.heap_start;
         }
     }
-}
\ No newline at end of file
+}
+
+impl HeapStatistics for BumpAllocator {
+    fn stats(&mut self) -> HeapStats {
+        // memory is only reclaimed once all allocations are freed, so everything
+        // below `next` counts as allocated
+        HeapStats {
+            heap_size: self.heap_end - self.heap_start,
+            bytes_allocated: self.next - self.heap_start,
+            bytes_free: self.heap_end - self.next,
+            allocations: self.total_allocations,
+            deallocations: self.total_deallocations,
+            largest_free_region: self.heap_end - self.next,
+            ..HeapStats::default()
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use super::align_up;
+use crate::serial_println;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr, slice};
+use spin::Mutex;
+
+/// The number of guard bytes before and after each allocation.
+const GUARD_SIZE: usize = 16;
+
+/// The pattern of the guard bytes.
+const GUARD_BYTE: u8 = 0xFD;
+
+/// The pattern with which fresh allocations are filled.
+const ALLOC_POISON: u8 = 0xAA;
+
+/// The pattern with which freed allocations are filled.
+const FREE_POISON: u8 = 0xDD;
+
+/// The number of freed allocations that are held back before they are
+/// returned to the inner allocator.
+const QUARANTINE_SIZE: usize = 16;
+
+const STATE_ALLOCATED: usize = 0xA110_CA7E_A110_CA7E;
+const STATE_FREED: usize = 0xF4EE_DF4E_EDF4_EEDF;
+
+/// The bookkeeping data that is stored directly before the front guard.
+#[repr(C)]
+struct Header {
+    /// Free list allocators store their nodes at the start of freed blocks,
+    /// so these words may be overwritten once the block is returned.
+    _reserved: [usize; 2],
+    state: usize,
+    size: usize,
+    align: usize,
+}
+
+impl Header {
+    fn layout(&self) -> Layout {
+        Layout::from_size_align(self.size, self.align).unwrap()
+    }
+}
+
+/// A heap corruption that is detected when an allocation is freed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Violation {
+    DoubleFree,
+    InvalidPointer,
+    LayoutMismatch,
+    BufferUnderflow,
+    BufferOverflow,
+}
+
+impl Violation {
+    fn as_str(self) -> &'static str {
+        match self {
+            Violation::DoubleFree => "double free",
+            Violation::InvalidPointer => "free of invalid pointer",
+            Violation::LayoutMismatch => "layout mismatch",
+            Violation::BufferUnderflow => "buffer underflow",
+            Violation::BufferOverflow => "buffer overflow",
+        }
+    }
+}
+
+/// A freed allocation that is not yet returned to the inner allocator.
+#[derive(Clone, Copy)]
+struct QuarantinedBlock {
+    ptr: usize,
+    layout: Layout,
+}
+
+struct Quarantine {
+    blocks: [Option<QuarantinedBlock>; QUARANTINE_SIZE],
+    next: usize,
+}
+
+/// A wrapper around a `GlobalAlloc` implementation that detects heap corruption.
+///
+/// Every allocation is surrounded by guard bytes and filled with a poison
+/// pattern, and freed allocations are poisoned too. On `dealloc`, the guard
+/// bytes are validated and double frees and layout mismatches are detected.
+/// Freed allocations are kept in a small quarantine before they are returned to
+/// the inner allocator, so that writes after free can be detected as well.
+///
+/// Violations are reported together with the allocation's layout over the
+/// serial interface, followed by a panic.
+pub struct DebugAllocator<A: 'static> {
+    inner: &'static A,
+    quarantine: Mutex<Quarantine>,
+}
+
+impl<A> DebugAllocator<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        DebugAllocator {
+            inner,
+            quarantine: Mutex::new(Quarantine {
+                blocks: [None; QUARANTINE_SIZE],
+                next: 0,
+            }),
+        }
+    }
+
+    /// Returns the wrapped allocator.
+    pub fn inner(&self) -> &'static A {
+        self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (inner_layout, offset) = match inner_layout(layout) {
+            Some(l) => l,
+            None => return ptr::null_mut(),
+        };
+        let block = self.inner.alloc(inner_layout);
+        if block.is_null() {
+            return ptr::null_mut();
+        }
+
+        let ptr = block.add(offset);
+        header(ptr).write(Header {
+            _reserved: [0; 2],
+            state: STATE_ALLOCATED,
+            size: layout.size(),
+            align: layout.align(),
+        });
+        ptr::write_bytes(ptr.sub(GUARD_SIZE), GUARD_BYTE, GUARD_SIZE);
+        ptr::write_bytes(ptr, ALLOC_POISON, layout.size());
+        ptr::write_bytes(ptr.add(layout.size()), GUARD_BYTE, GUARD_SIZE);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match check_allocation(ptr, layout) {
+            Ok(()) => {}
+            Err(violation @ Violation::DoubleFree) => {
+                report(violation.as_str(), ptr, (*header(ptr)).layout())
+            }
+            Err(violation @ Violation::LayoutMismatch) => {
+                serial_println!(
+                    "dealloc called with size {}, align {}",
+                    layout.size(),
+                    layout.align()
+                );
+                report(violation.as_str(), ptr, (*header(ptr)).layout());
+            }
+            Err(violation) => report(violation.as_str(), ptr, layout),
+        }
+
+        (*header(ptr)).state = STATE_FREED;
+        ptr::write_bytes(ptr, FREE_POISON, layout.size());
+
+        let evicted = {
+            let mut quarantine = self.quarantine.lock();
+            let index = quarantine.next;
+            quarantine.next = (index + 1) % QUARANTINE_SIZE;
+            mem::replace(
+                &mut quarantine.blocks[index],
+                Some(QuarantinedBlock {
+                    ptr: ptr as usize,
+                    layout,
+                }),
+            )
+        };
+        if let Some(block) = evicted {
+            self.release(block);
+        }
+    }
+}
+
+impl<A: GlobalAlloc> DebugAllocator<A> {
+    /// Returns a block from the quarantine to the inner allocator.
+    unsafe fn release(&self, block: QuarantinedBlock) {
+        let ptr = block.ptr as *mut u8;
+        if !is_filled(ptr, block.layout.size(), FREE_POISON) {
+            report("write after free", ptr, block.layout);
+        }
+        let (inner_layout, offset) = inner_layout(block.layout).unwrap();
+        self.inner.dealloc(ptr.sub(offset), inner_layout);
+    }
+}
+
+/// Returns the layout of the inner block for the given allocation and the
+/// offset of the allocation in that block.
+fn inner_layout(layout: Layout) -> Option<(Layout, usize)> {
+    let align = layout.align().max(mem::align_of::<Header>());
+    let offset = align_up(mem::size_of::<Header>() + GUARD_SIZE, align);
+    let size = offset
+        .checked_add(layout.size())?
+        .checked_add(GUARD_SIZE)?;
+    Some((Layout::from_size_align(size, align).ok()?, offset))
+}
+
+/// Returns a pointer to the header of the given allocation.
+fn header(ptr: *mut u8) -> *mut Header {
+    (ptr as usize - GUARD_SIZE - mem::size_of::<Header>()) as *mut Header
+}
+
+/// Checks the header and the guard bytes of an allocation that is freed with
+/// the given layout.
+unsafe fn check_allocation(ptr: *mut u8, layout: Layout) -> Result<(), Violation> {
+    let header = &*header(ptr);
+    match header.state {
+        STATE_ALLOCATED => {}
+        STATE_FREED => return Err(Violation::DoubleFree),
+        _ => return Err(Violation::InvalidPointer),
+    }
+    if header.size != layout.size() || header.align != layout.align() {
+        return Err(Violation::LayoutMismatch);
+    }
+    if !is_filled(ptr.sub(GUARD_SIZE), GUARD_SIZE, GUARD_BYTE) {
+        return Err(Violation::BufferUnderflow);
+    }
+    if !is_filled(ptr.add(layout.size()), GUARD_SIZE, GUARD_BYTE) {
+        return Err(Violation::BufferOverflow);
+    }
+    Ok(())
+}
+
+/// Checks whether all bytes of the given memory range have the given value.
+unsafe fn is_filled(start: *const u8, len: usize, value: u8) -> bool {
+    slice::from_raw_parts(start, len).iter().all(|&b| b == value)
+}
+
+/// Reports a heap corruption over the serial interface and panics.
+fn report(violation: &str, ptr: *mut u8, layout: Layout) -> ! {
+    serial_println!(
+        "heap corruption: {} at {:p} (size {}, align {})",
+        violation,
+        ptr,
+        layout.size(),
+        layout.align()
+    );
+    panic!("heap corruption: {}", violation);
+}
+
+#[cfg(test)]
+use super::fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(test)]
+use crate::serial_print;
+
+#[test_case]
+fn test_allocations_are_poisoned() {
+    serial_print!("test_allocations_are_poisoned... ");
+    let allocator = DebugAllocator::new(test_allocator!(FixedSizeBlockAllocator));
+
+    let layout = Layout::from_size_align(24, 32).unwrap();
+    let ptr = unsafe { allocator.alloc(layout) };
+    assert!(!ptr.is_null());
+    assert_eq!(ptr as usize % 32, 0);
+    unsafe {
+        assert!(is_filled(ptr, 24, ALLOC_POISON));
+        assert!(is_filled(ptr.add(24), GUARD_SIZE, GUARD_BYTE));
+        allocator.dealloc(ptr, layout);
+        // the allocation stays in the quarantine, so it can still be inspected
+        assert!(is_filled(ptr, 24, FREE_POISON));
+        assert_eq!((*header(ptr)).state, STATE_FREED);
+        assert_eq!(check_allocation(ptr, layout), Err(Violation::DoubleFree));
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_overflow_is_detected() {
+    serial_print!("test_overflow_is_detected... ");
+    let allocator = DebugAllocator::new(test_allocator!(FixedSizeBlockAllocator));
+
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    let ptr = unsafe { allocator.alloc(layout) };
+    assert!(!ptr.is_null());
+    unsafe {
+        assert_eq!(check_allocation(ptr, layout), Ok(()));
+        // write one byte past the end into the trailing guard
+        ptr.add(24).write(0);
+        assert_eq!(check_allocation(ptr, layout), Err(Violation::BufferOverflow));
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_underflow_is_detected() {
+    serial_print!("test_underflow_is_detected... ");
+    let allocator = DebugAllocator::new(test_allocator!(FixedSizeBlockAllocator));
+
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    let ptr = unsafe { allocator.alloc(layout) };
+    assert!(!ptr.is_null());
+    unsafe {
+        // write one byte before the start into the front guard
+        ptr.sub(1).write(0);
+        assert_eq!(check_allocation(ptr, layout), Err(Violation::BufferUnderflow));
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_layout_mismatch_is_detected() {
+    serial_print!("test_layout_mismatch_is_detected... ");
+    let allocator = DebugAllocator::new(test_allocator!(FixedSizeBlockAllocator));
+
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    let ptr = unsafe { allocator.alloc(layout) };
+    assert!(!ptr.is_null());
+    let wrong_size = Layout::from_size_align(32, 8).unwrap();
+    let wrong_align = Layout::from_size_align(24, 16).unwrap();
+    unsafe {
+        assert_eq!(check_allocation(ptr, wrong_size), Err(Violation::LayoutMismatch));
+        assert_eq!(check_allocation(ptr, wrong_align), Err(Violation::LayoutMismatch));
+        allocator.dealloc(ptr, layout);
+    }
+    serial_println!("[ok]");
+}
@@ -1,6 +1,6 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
-use super::Locked;
+use super::{HeapStatistics, HeapStats, Locked};
 use core::{mem, ptr::NonNull};
 
 struct ListNode {
@@ -11,11 +11,25 @@ struct ListNode {
 ///
 /// The sizes must each be power of 2 because they are also used as
 /// the block alignment (alignments must be always powers of 2).
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// The number of bytes of the fallback heap that the blocks of each list may
+/// occupy before freed blocks are returned to the fallback allocator.
+const FREE_LIST_LIMIT: usize = 8 * 1024;
+
+/// The smallest allocation of the fallback allocator, which rounds smaller
+/// sizes up to this to be able to store a free list node in freed memory.
+const FALLBACK_MIN_SIZE: usize = 2 * mem::size_of::<usize>();
 
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    allocated_blocks: [usize; BLOCK_SIZES.len()],
+    free_blocks: [usize; BLOCK_SIZES.len()],
+    fallback_used: usize,
+    bytes_allocated: usize,
+    allocations: usize,
+    deallocations: usize,
 }
 
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
@@ -23,23 +37,32 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
         let mut allocator = self.lock();
         match list_index(layout) {
             Some(index) => {
-                match allocator.list_heads[index].take() {
+                let ptr = match allocator.list_heads[index].take() {
                     Some(node) => {
                         allocator.list_heads[index] = node.next.take();
+                        allocator.free_blocks[index] -= 1;
                         node as *mut ListNode as *mut u8
                     },
                     None => {
                         // no block exists in list => allocate new block
-                        let block_size = BLOCK_SIZES[index];
-                        // only works if all block sizes are a power of 2
-                        let block_align = block_size;
-                        let layout = Layout::from_size_align(block_size, block_align)
-                            .unwrap();
-                        allocator.fallback_alloc(layout)
+                        allocator.fallback_alloc(block_layout(index))
                     }
+                };
+                if !ptr.is_null() {
+                    allocator.allocated_blocks[index] += 1;
+                    allocator.bytes_allocated += BLOCK_SIZES[index];
+                    allocator.allocations += 1;
                 }
+                ptr
             },
-            None => allocator.fallback_alloc(layout),
+            None => {
+                let ptr = allocator.fallback_alloc(layout);
+                if !ptr.is_null() {
+                    allocator.bytes_allocated += layout.size();
+                    allocator.allocations += 1;
+                }
+                ptr
+            }
         }
     }
 
@@ -47,21 +70,34 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
         let mut allocator = self.lock();
         match list_index(layout) {
             Some(index) => {
-                let new_node = ListNode{
-                    next: allocator.list_heads[index].take()
-                };
-                // verify that block has size and alignment required for storing node
-                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-                let new_node_ptr = ptr as *mut ListNode;
-                new_node_ptr.write(new_node);
-                allocator.list_heads[index] = Some(&mut *new_node_ptr);
+                allocator.allocated_blocks[index] -= 1;
+                allocator.bytes_allocated -= BLOCK_SIZES[index];
+
+                let footprint = fallback_size(block_layout(index));
+                if (allocator.free_blocks[index] + 1) * footprint > FREE_LIST_LIMIT {
+                    // enough blocks of this size are cached => return it to the fallback
+                    let ptr = NonNull::new(ptr).unwrap();
+                    allocator.fallback_dealloc(ptr, block_layout(index));
+                } else {
+                    let new_node = ListNode{
+                        next: allocator.list_heads[index].take()
+                    };
+                    // verify that block has size and alignment required for storing node
+                    assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                    assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                    let new_node_ptr = ptr as *mut ListNode;
+                    new_node_ptr.write(new_node);
+                    allocator.list_heads[index] = Some(&mut *new_node_ptr);
+                    allocator.free_blocks[index] += 1;
+                }
             },
             None => {
                 let ptr = NonNull::new(ptr).unwrap();
-                allocator.fallback_allocator.deallocate(ptr, layout);
+                allocator.fallback_dealloc(ptr, layout);
+                allocator.bytes_allocated -= layout.size();
             }
         }
+        allocator.deallocations += 1;
     }
 }
 
@@ -73,11 +109,34 @@ fn list_index(layout: Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
+/// Returns the number of bytes that an allocation with the given layout takes
+/// up in the fallback heap.
+fn fallback_size(layout: Layout) -> usize {
+    let align = mem::align_of::<usize>();
+    let size = (layout.size() + align - 1) / align * align;
+    size.max(FALLBACK_MIN_SIZE)
+}
+
+/// Returns the layout with which blocks of the given list are allocated from
+/// the fallback allocator.
+fn block_layout(index: usize) -> Layout {
+    let block_size = BLOCK_SIZES[index];
+    // only works if all block sizes are a power of 2
+    let block_align = block_size;
+    Layout::from_size_align(block_size, block_align).unwrap()
+}
+
 impl FixedSizeBlockAllocator {
     pub const fn new() -> Self {
         FixedSizeBlockAllocator {
             list_heads: [None; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            allocated_blocks: [0; BLOCK_SIZES.len()],
+            free_blocks: [0; BLOCK_SIZES.len()],
+            fallback_used: 0,
+            bytes_allocated: 0,
+            allocations: 0,
+            deallocations: 0,
         }
     }
 
@@ -91,10 +150,158 @@ impl FixedSizeBlockAllocator {
     }
 
     /// Allocates using the fallback allocator.
+    ///
+    /// If the fallback allocator runs out of memory, the blocks in the free
+    /// lists are returned to it first. Only if that doesn't help, the heap grows.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            self.fallback_used += fallback_size(layout);
+            return ptr.as_ptr();
+        }
+
+        if self.reclaim_free_blocks() > 0 {
+            if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+                self.fallback_used += fallback_size(layout);
+                return ptr.as_ptr();
+            }
+        }
+
+        // out of memory => map more pages at the end of the heap and retry
+        if !super::grow_fallback_heap(&mut self.fallback_allocator, layout) {
+            return ptr::null_mut();
+        }
+
         match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
+            Ok(ptr) => {
+                self.fallback_used += fallback_size(layout);
+                ptr.as_ptr()
+            },
             Err(_) => ptr::null_mut(),
         }
     }
+
+    /// Returns memory to the fallback allocator.
+    unsafe fn fallback_dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        self.fallback_allocator.deallocate(ptr, layout);
+        self.fallback_used -= fallback_size(layout);
+    }
+
+    /// Returns all blocks in the free lists to the fallback allocator.
+    ///
+    /// Returns the number of reclaimed bytes.
+    fn reclaim_free_blocks(&mut self) -> usize {
+        let mut reclaimed = 0;
+        for index in 0..BLOCK_SIZES.len() {
+            while let Some(node) = self.list_heads[index].take() {
+                self.list_heads[index] = node.next.take();
+                let ptr = NonNull::from(node).cast::<u8>();
+                // all blocks were allocated from the fallback allocator with this layout
+                unsafe { self.fallback_dealloc(ptr, block_layout(index)) };
+                self.free_blocks[index] -= 1;
+                reclaimed += BLOCK_SIZES[index];
+            }
+        }
+        reclaimed
+    }
+}
+
+impl HeapStatistics for FixedSizeBlockAllocator {
+    fn stats(&mut self) -> HeapStats {
+        let fallback_free = self.fallback_allocator.size() - self.fallback_used;
+        let mut stats = HeapStats {
+            heap_size: self.fallback_allocator.size(),
+            bytes_allocated: self.bytes_allocated,
+            bytes_free: fallback_free,
+            allocations: self.allocations,
+            deallocations: self.deallocations,
+            // an upper bound, see `HeapStats::largest_free_region`
+            largest_free_region: fallback_free,
+            ..HeapStats::default()
+        };
+
+        for (index, &block_size) in BLOCK_SIZES.iter().enumerate() {
+            let class = &mut stats.size_classes[index];
+            class.block_size = block_size;
+            class.allocated = self.allocated_blocks[index];
+            class.free = self.free_blocks[index];
+
+            stats.bytes_free += class.free * block_size;
+            if class.free > 0 {
+                stats.largest_free_region = stats.largest_free_region.max(block_size);
+            }
+        }
+
+        stats
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[cfg(test)]
+use super::TEST_HEAP_SIZE;
+
+#[test_case]
+fn test_free_lists_are_bounded() {
+    serial_print!("test_free_lists_are_bounded... ");
+    // more blocks than the free list can hold, with room to spare in the heap
+    static mut PTRS: [*mut u8; 768] = [ptr::null_mut(); 768];
+    let allocator = test_allocator!(FixedSizeBlockAllocator);
+    let ptrs = unsafe { &mut PTRS };
+
+    let layout = Layout::from_size_align(8, 8).unwrap();
+    for ptr in ptrs.iter_mut() {
+        *ptr = unsafe { allocator.alloc(layout) };
+        assert!(!ptr.is_null());
+    }
+    for &ptr in ptrs.iter() {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+
+    let stats = allocator.stats();
+    let footprint = fallback_size(layout);
+    assert!(ptrs.len() * footprint < TEST_HEAP_SIZE);
+    assert!(stats.size_classes[0].free > 0);
+    assert!(stats.size_classes[0].free * footprint <= FREE_LIST_LIMIT);
+    assert_eq!(stats.size_classes[0].allocated, 0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_memory_reused_across_size_classes() {
+    serial_print!("test_memory_reused_across_size_classes... ");
+    static mut PTRS: [*mut u8; TEST_HEAP_SIZE / 8] = [ptr::null_mut(); TEST_HEAP_SIZE / 8];
+    let allocator = test_allocator!(FixedSizeBlockAllocator);
+    let ptrs = unsafe { &mut PTRS };
+
+    // fill the whole heap with small blocks, then free them again
+    let small = Layout::from_size_align(8, 8).unwrap();
+    let mut count = 0;
+    for ptr in ptrs.iter_mut() {
+        *ptr = unsafe { allocator.alloc(small) };
+        if ptr.is_null() {
+            break;
+        }
+        count += 1;
+    }
+    assert!(count > 0);
+    for &ptr in ptrs[..count].iter() {
+        unsafe { allocator.dealloc(ptr, small) };
+    }
+
+    // the small blocks must be usable for large blocks now
+    let large = Layout::from_size_align(2048, 2048).unwrap();
+    let mut large_count = 0;
+    for ptr in ptrs.iter_mut() {
+        *ptr = unsafe { allocator.alloc(large) };
+        if ptr.is_null() {
+            break;
+        }
+        large_count += 1;
+    }
+    assert!(large_count >= TEST_HEAP_SIZE / 2048 - 1);
+    for &ptr in ptrs[..large_count].iter() {
+        unsafe { allocator.dealloc(ptr, large) };
+    }
+    serial_println!("[ok]");
 }
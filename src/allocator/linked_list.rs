@@ -1,5 +1,5 @@
 use super::align_up;
-use super::Locked;
+use super::{HeapStatistics, HeapStats, Locked};
 use core::{mem, ptr};
 use alloc::alloc::{GlobalAlloc, Layout};
 
@@ -27,6 +27,11 @@ impl ListNode {
 
 pub struct LinkedListAllocator {
     head: ListNode,
+    heap_start: usize,
+    heap_size: usize,
+    bytes_allocated: usize,
+    allocations: usize,
+    deallocations: usize,
 }
 
 impl LinkedListAllocator {
@@ -34,6 +39,11 @@ impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            heap_start: 0,
+            heap_size: 0,
+            bytes_allocated: 0,
+            allocations: 0,
+            deallocations: 0,
         }
     }
 
@@ -44,20 +54,67 @@ impl LinkedListAllocator {
     /// called only once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.add_free_region(heap_start, heap_size);
+        self.heap_start = heap_start;
+        self.heap_size = heap_size;
     }
 
-    /// Adds the given memory region to the front of the list.
+    /// Grows the heap so that it can hold an allocation of the given size and
+    /// alignment, and adds the new memory to the free list.
+    ///
+    /// Only succeeds for the kernel heap. Returns whether the heap grew.
+    fn grow(&mut self, size: usize, align: usize) -> bool {
+        let heap_end = self.heap_start + self.heap_size;
+        let grown = super::grow_heap(heap_end, size + align);
+        if grown == 0 {
+            return false;
+        }
+        // the new pages directly follow the end of the heap and are unused
+        unsafe { self.add_free_region(heap_end, grown) };
+        self.heap_size += grown;
+        true
+    }
+
+    /// Adds the given memory region to the list, which is kept sorted by address.
+    ///
+    /// The region is merged with the free regions directly before and after it.
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         // ensure that the freed region is capable of holding ListNode
         assert!(align_up(addr, mem::align_of::<ListNode>()) == addr);
         assert!(size >= mem::size_of::<ListNode>());
 
-        // create a new list node and append it at the start of the list
-        let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
-        let node_ptr = addr as *mut ListNode;
-        node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr)
+        // find the last region that starts before the freed region (or the head)
+        let mut current = &mut self.head;
+        while current.next.as_ref().map_or(false, |next| next.start_addr() < addr) {
+            current = current.next.as_mut().unwrap();
+        }
+
+        let mut size = size;
+        let mut next = current.next.take();
+        if let Some(ref next_region) = next {
+            assert!(addr + size <= next_region.start_addr(), "freed region overlaps a free region");
+        }
+        // the head node has size 0 and is never merged
+        assert!(current.size == 0 || current.end_addr() <= addr, "freed region overlaps a free region");
+
+        // merge with the following region if they are adjacent
+        if next.as_ref().map_or(false, |next_region| next_region.start_addr() == addr + size) {
+            let next_region = next.take().unwrap();
+            size += next_region.size;
+            next = next_region.next.take();
+        }
+
+        if current.size > 0 && current.end_addr() == addr {
+            // merge with the preceding region
+            current.size += size;
+            current.next = next;
+        } else {
+            // create a new list node and insert it after `current`
+            let mut node = ListNode::new(size);
+            node.next = next;
+            let node_ptr = addr as *mut ListNode;
+            node_ptr.write(node);
+            current.next = Some(&mut *node_ptr)
+        }
     }
 
     /// Looks for a free region with the given size and alignment and removes
@@ -131,12 +188,18 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         let (size, align) = LinkedListAllocator::size_align(layout);
 
         let mut allocator = self.lock();
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
+        let mut found = allocator.find_region(size, align);
+        if found.is_none() && allocator.grow(size, align) {
+            found = allocator.find_region(size, align);
+        }
+        if let Some((region, alloc_start)) = found {
             let alloc_end = alloc_start.checked_add(size).expect("overflow!");
             let excess_size = region.end_addr() - alloc_end;
             if excess_size > 0 {
                 allocator.add_free_region(alloc_end, excess_size);
             }
+            allocator.bytes_allocated += size;
+            allocator.allocations += 1;
             alloc_start as *mut u8
         } else {
             ptr::null_mut()
@@ -147,6 +210,93 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         // perform ayout adjustments
         let (size, _) = LinkedListAllocator::size_align(layout);
 
-        self.lock().add_free_region(ptr as usize, size)
+        let mut allocator = self.lock();
+        allocator.add_free_region(ptr as usize, size);
+        allocator.bytes_allocated -= size;
+        allocator.deallocations += 1;
     }
 }
+
+impl HeapStatistics for LinkedListAllocator {
+    fn stats(&mut self) -> HeapStats {
+        let mut bytes_free = 0;
+        let mut largest_free_region = 0;
+        let mut current = &self.head;
+        while let Some(region) = current.next.as_ref() {
+            bytes_free += region.size;
+            largest_free_region = largest_free_region.max(region.size);
+            current = &**region;
+        }
+
+        HeapStats {
+            heap_size: self.heap_size,
+            bytes_allocated: self.bytes_allocated,
+            bytes_free,
+            allocations: self.allocations,
+            deallocations: self.deallocations,
+            largest_free_region,
+            ..HeapStats::default()
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[cfg(test)]
+use super::TEST_HEAP_SIZE;
+
+/// Checks that the complete test heap can be allocated at once again.
+#[cfg(test)]
+fn assert_heap_coalesced(allocator: &Locked<LinkedListAllocator>) {
+    assert_eq!(allocator.stats().largest_free_region, TEST_HEAP_SIZE);
+    let layout = Layout::from_size_align(TEST_HEAP_SIZE, 8).unwrap();
+    let ptr = unsafe { allocator.alloc(layout) };
+    assert!(!ptr.is_null());
+    unsafe { allocator.dealloc(ptr, layout) };
+}
+
+#[test_case]
+fn test_interleaved_frees_coalesce() {
+    serial_print!("test_interleaved_frees_coalesce... ");
+    let allocator = test_allocator!(LinkedListAllocator);
+
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let mut ptrs = [ptr::null_mut(); 128];
+    for ptr in ptrs.iter_mut() {
+        *ptr = unsafe { allocator.alloc(layout) };
+        assert!(!ptr.is_null());
+    }
+    // free every other block first, then the blocks in between
+    for &ptr in ptrs.iter().step_by(2) {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+    for &ptr in ptrs.iter().skip(1).step_by(2) {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+
+    assert_heap_coalesced(&allocator);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_scattered_frees_coalesce() {
+    serial_print!("test_scattered_frees_coalesce... ");
+    let allocator = test_allocator!(LinkedListAllocator);
+
+    let sizes = [16, 48, 128, 24];
+    let layout = |i: usize| Layout::from_size_align(sizes[i % sizes.len()], 8).unwrap();
+    let mut ptrs = [ptr::null_mut(); 64];
+    for (i, ptr) in ptrs.iter_mut().enumerate() {
+        *ptr = unsafe { allocator.alloc(layout(i)) };
+        assert!(!ptr.is_null());
+    }
+    // free in a scattered order (5 is coprime to 64, so every block is visited once)
+    for step in 0..ptrs.len() {
+        let i = step * 5 % ptrs.len();
+        unsafe { allocator.dealloc(ptrs[i], layout(i)) };
+    }
+
+    assert_heap_coalesced(&allocator);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,728 @@
+use super::{HeapStatistics, HeapStats, Locked};
+use crate::serial_println;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+
+/// The size of a slab.
+///
+/// Slabs are aligned to their size, so the slab of an object is found by
+/// rounding the object's address down.
+const SLAB_SIZE: usize = 4096;
+
+/// The largest object size that a cache supports, so that every slab holds
+/// at least a few objects.
+const MAX_OBJECT_SIZE: usize = SLAB_SIZE / 4;
+
+/// The object sizes of the general purpose caches that serve `GlobalAlloc`.
+///
+/// The sizes must each be power of 2 because they are also used as the
+/// object alignment.
+const SIZE_CLASSES: &[usize] = &[16, 32, 64, 128, 256, 512, 1024];
+
+/// The maximum number of named caches.
+const MAX_NAMED_CACHES: usize = 16;
+
+struct FreeObject {
+    next: Option<&'static mut FreeObject>,
+}
+
+/// The header at the start of every slab.
+///
+/// The slab lists of a cache are doubly linked through the start addresses of
+/// the slabs, so that a slab is unlinked in constant time when one of its
+/// objects is freed.
+struct Slab {
+    next: Option<usize>,
+    prev: Option<usize>,
+    free_objects: Option<&'static mut FreeObject>,
+    in_use: usize,
+    /// The object size of the cache that the slab belongs to.
+    object_size: usize,
+}
+
+/// A cache of equally sized objects that are carved out of slabs.
+struct SlabCache {
+    name: &'static str,
+    object_size: usize,
+    first_object_offset: usize,
+    objects_per_slab: usize,
+    /// Slabs with at least one free object.
+    partial_slabs: Option<usize>,
+    /// Slabs without free objects.
+    full_slabs: Option<usize>,
+    /// A slab without objects in use, which is kept so that allocating and
+    /// freeing objects at a slab boundary doesn't create and release a slab
+    /// every time. Any further empty slab is released.
+    empty_slab: Option<usize>,
+    slabs: usize,
+    objects_in_use: usize,
+    allocations: usize,
+    deallocations: usize,
+}
+
+impl SlabCache {
+    /// Creates an empty cache.
+    ///
+    /// `object_size` must be a multiple of `object_align`, which must be a power
+    /// of 2 that is at least the alignment of `FreeObject`.
+    const fn new(name: &'static str, object_size: usize, object_align: usize) -> Self {
+        let first_object_offset = (mem::size_of::<Slab>() + object_align - 1) & !(object_align - 1);
+        SlabCache {
+            name,
+            object_size,
+            first_object_offset,
+            objects_per_slab: (SLAB_SIZE - first_object_offset) / object_size,
+            partial_slabs: None,
+            full_slabs: None,
+            empty_slab: None,
+            slabs: 0,
+            objects_in_use: 0,
+            allocations: 0,
+            deallocations: 0,
+        }
+    }
+
+    /// Creates an empty cache for objects with the given layout.
+    ///
+    /// Returns `None` if the objects are too large for slabs.
+    fn with_layout(name: &'static str, layout: Layout) -> Option<Self> {
+        let align = layout.align().max(mem::align_of::<FreeObject>());
+        let size = super::align_up(layout.size().max(mem::size_of::<FreeObject>()), align);
+        if size > MAX_OBJECT_SIZE || align > MAX_OBJECT_SIZE {
+            return None;
+        }
+        Some(Self::new(name, size, align))
+    }
+
+    /// Takes a free object from the cache, reusing the empty slab or creating a
+    /// new slab if necessary.
+    fn alloc(&mut self, heap: &mut linked_list_allocator::Heap) -> *mut u8 {
+        let slab_addr = match self.partial_slabs {
+            Some(slab_addr) => slab_addr,
+            None => {
+                let slab_addr = match self.empty_slab.take() {
+                    Some(slab_addr) => slab_addr,
+                    None => match self.new_slab(heap) {
+                        Some(slab_addr) => slab_addr,
+                        None => return ptr::null_mut(),
+                    },
+                };
+                unsafe { push(&mut self.partial_slabs, slab_addr) };
+                slab_addr
+            }
+        };
+
+        // the slab is in the partial list of this cache
+        let slab = unsafe { slab_at(slab_addr) };
+        let object = slab.free_objects.take().expect("partial slab has no free object");
+        slab.free_objects = object.next.take();
+        slab.in_use += 1;
+        if slab.in_use == self.objects_per_slab {
+            unsafe {
+                unlink(&mut self.partial_slabs, slab_addr);
+                push(&mut self.full_slabs, slab_addr);
+            }
+        }
+
+        self.objects_in_use += 1;
+        self.allocations += 1;
+        object as *mut FreeObject as *mut u8
+    }
+
+    /// Returns an object to its slab.
+    ///
+    /// A slab that becomes empty is kept as the cache's empty slab, or released
+    /// if the cache has one already.
+    ///
+    /// This function is unsafe because the caller must guarantee that the object
+    /// was allocated from this cache and is no longer used.
+    unsafe fn dealloc(&mut self, ptr: *mut u8, heap: &mut linked_list_allocator::Heap) {
+        let slab_addr = ptr as usize & !(SLAB_SIZE - 1);
+        let slab = slab_at(slab_addr);
+        assert_eq!(slab.object_size, self.object_size, "object does not belong to this cache");
+        let was_full = slab.in_use == self.objects_per_slab;
+
+        let object_ptr = ptr as *mut FreeObject;
+        object_ptr.write(FreeObject {
+            next: slab.free_objects.take(),
+        });
+        slab.free_objects = Some(&mut *object_ptr);
+        slab.in_use -= 1;
+        self.objects_in_use -= 1;
+        self.deallocations += 1;
+
+        let list = if was_full {
+            &mut self.full_slabs
+        } else {
+            &mut self.partial_slabs
+        };
+        if slab.in_use == 0 {
+            unlink(list, slab_addr);
+            if self.empty_slab.is_none() {
+                self.empty_slab = Some(slab_addr);
+            } else {
+                // the slab is empty => give its memory back to the heap
+                heap.deallocate(NonNull::new_unchecked(slab_addr as *mut u8), slab_layout());
+                self.slabs -= 1;
+            }
+        } else if was_full {
+            unlink(list, slab_addr);
+            push(&mut self.partial_slabs, slab_addr);
+        }
+    }
+
+    /// Gives the kept empty slab back to the heap.
+    fn release_empty_slab(&mut self, heap: &mut linked_list_allocator::Heap) {
+        if let Some(slab_addr) = self.empty_slab.take() {
+            // the slab has no objects in use and is in no list
+            unsafe { heap.deallocate(NonNull::new_unchecked(slab_addr as *mut u8), slab_layout()) };
+            self.slabs -= 1;
+        }
+    }
+
+    /// Allocates a new slab from the heap and splits it into free objects.
+    ///
+    /// Returns the start address of the slab, which is in no list yet.
+    fn new_slab(&mut self, heap: &mut linked_list_allocator::Heap) -> Option<usize> {
+        let layout = slab_layout();
+        let slab_start = match heap.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr() as usize,
+            Err(()) => {
+                if !super::grow_fallback_heap(heap, layout) {
+                    return None;
+                }
+                heap.allocate_first_fit(layout).ok()?.as_ptr() as usize
+            }
+        };
+
+        let slab_ptr = slab_start as *mut Slab;
+        let slab = unsafe {
+            slab_ptr.write(Slab {
+                next: None,
+                prev: None,
+                free_objects: None,
+                in_use: 0,
+                object_size: self.object_size,
+            });
+            &mut *slab_ptr
+        };
+        // push the objects in reverse so that they are handed out in address order
+        for i in (0..self.objects_per_slab).rev() {
+            let object_ptr =
+                (slab_start + self.first_object_offset + i * self.object_size) as *mut FreeObject;
+            unsafe {
+                object_ptr.write(FreeObject {
+                    next: slab.free_objects.take(),
+                });
+                slab.free_objects = Some(&mut *object_ptr);
+            }
+        }
+
+        self.slabs += 1;
+        Some(slab_start)
+    }
+
+    fn stats(&self) -> SlabCacheStats {
+        SlabCacheStats {
+            name: self.name,
+            object_size: self.object_size,
+            slabs: self.slabs,
+            objects_in_use: self.objects_in_use,
+            capacity: self.slabs * self.objects_per_slab,
+            allocations: self.allocations,
+            deallocations: self.deallocations,
+        }
+    }
+}
+
+/// Identifies a named cache of a `SlabAllocator`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheId(usize);
+
+/// A snapshot of the state of a single slab cache.
+#[derive(Debug, Clone, Copy)]
+pub struct SlabCacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    /// The number of slabs that currently belong to the cache.
+    pub slabs: usize,
+    /// The number of objects that are currently allocated.
+    pub objects_in_use: usize,
+    /// The number of objects that fit into the cache's slabs.
+    pub capacity: usize,
+    pub allocations: usize,
+    pub deallocations: usize,
+}
+
+impl SlabCacheStats {
+    /// Prints the statistics over the serial interface.
+    pub fn dump(&self) {
+        serial_println!(
+            "slab {}: {} byte objects, {}/{} in use, {} slabs, {} allocations, {} deallocations",
+            self.name,
+            self.object_size,
+            self.objects_in_use,
+            self.capacity,
+            self.slabs,
+            self.allocations,
+            self.deallocations
+        );
+    }
+}
+
+/// An allocator that serves objects from page-sized slabs.
+///
+/// Allocations through `GlobalAlloc` go to general purpose caches with
+/// power-of-2 object sizes; larger allocations and the slabs themselves come
+/// from a fallback allocator. Kernel objects of a fixed type can get their
+/// own named cache through `create_cache` or `ObjectCache`.
+pub struct SlabAllocator {
+    size_caches: [SlabCache; SIZE_CLASSES.len()],
+    named_caches: [Option<SlabCache>; MAX_NAMED_CACHES],
+    fallback_allocator: linked_list_allocator::Heap,
+    /// Bytes of large allocations that went directly to the fallback allocator.
+    large_allocated: usize,
+    large_allocations: usize,
+    large_deallocations: usize,
+}
+
+impl SlabAllocator {
+    pub const fn new() -> Self {
+        SlabAllocator {
+            size_caches: [
+                SlabCache::new("size-16", 16, 16),
+                SlabCache::new("size-32", 32, 32),
+                SlabCache::new("size-64", 64, 64),
+                SlabCache::new("size-128", 128, 128),
+                SlabCache::new("size-256", 256, 256),
+                SlabCache::new("size-512", 512, 512),
+                SlabCache::new("size-1024", 1024, 1024),
+            ],
+            named_caches: [None; MAX_NAMED_CACHES],
+            fallback_allocator: linked_list_allocator::Heap::empty(),
+            large_allocated: 0,
+            large_allocations: 0,
+            large_deallocations: 0,
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// This function is unsafe because the caller must guarantee that the given
+    /// heap bounds are valid and that the heap is unused. This method must be
+    /// called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    /// Creates a named cache for objects with the given layout.
+    ///
+    /// Returns `None` if the objects are too large for slabs or if all cache
+    /// slots are in use.
+    pub fn create_cache(&mut self, name: &'static str, layout: Layout) -> Option<CacheId> {
+        let cache = SlabCache::with_layout(name, layout)?;
+        let index = self.named_caches.iter().position(|c| c.is_none())?;
+        self.named_caches[index] = Some(cache);
+        Some(CacheId(index))
+    }
+
+    /// Removes the given named cache and frees its slot.
+    ///
+    /// Returns `false` and keeps the cache if objects are still allocated
+    /// from it.
+    pub fn destroy_cache(&mut self, id: CacheId) -> bool {
+        let SlabAllocator { named_caches, fallback_allocator, .. } = self;
+        let slot = &mut named_caches[id.0];
+        match slot {
+            Some(cache) if cache.objects_in_use == 0 => {
+                // only the empty slab is left once all objects are freed
+                cache.release_empty_slab(fallback_allocator);
+                debug_assert_eq!(cache.slabs, 0);
+                *slot = None;
+                true
+            }
+            Some(_) => false,
+            None => panic!("invalid cache id"),
+        }
+    }
+
+    /// Allocates an object from the given named cache.
+    ///
+    /// Returns a null pointer if the heap is exhausted.
+    pub fn alloc_object(&mut self, id: CacheId) -> *mut u8 {
+        let SlabAllocator { named_caches, fallback_allocator, .. } = self;
+        let cache = named_caches[id.0].as_mut().expect("invalid cache id");
+        cache.alloc(fallback_allocator)
+    }
+
+    /// Returns an object to the given named cache.
+    ///
+    /// This function is unsafe because the caller must guarantee that the object
+    /// was allocated from the same cache and is no longer used.
+    pub unsafe fn free_object(&mut self, id: CacheId, ptr: *mut u8) {
+        let SlabAllocator { named_caches, fallback_allocator, .. } = self;
+        let cache = named_caches[id.0].as_mut().expect("invalid cache id");
+        cache.dealloc(ptr, fallback_allocator)
+    }
+
+    /// Returns the statistics of the given named cache.
+    pub fn cache_stats(&self, id: CacheId) -> Option<SlabCacheStats> {
+        self.named_caches[id.0].as_ref().map(SlabCache::stats)
+    }
+
+    /// Allocates objects that are too large for the caches from the fallback
+    /// allocator, growing the heap if necessary.
+    fn large_alloc(&mut self, layout: Layout) -> *mut u8 {
+        let ptr = match self.fallback_allocator.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(()) => {
+                if !super::grow_fallback_heap(&mut self.fallback_allocator, layout) {
+                    return ptr::null_mut();
+                }
+                match self.fallback_allocator.allocate_first_fit(layout) {
+                    Ok(ptr) => ptr.as_ptr(),
+                    Err(()) => return ptr::null_mut(),
+                }
+            }
+        };
+        self.large_allocated += layout.size();
+        self.large_allocations += 1;
+        ptr
+    }
+
+    /// Returns the statistics of all caches, the general purpose caches first.
+    fn all_cache_stats(&self) -> [Option<SlabCacheStats>; SIZE_CLASSES.len() + MAX_NAMED_CACHES] {
+        let mut stats = [None; SIZE_CLASSES.len() + MAX_NAMED_CACHES];
+        for (slot, cache) in stats.iter_mut().zip(self.caches()) {
+            *slot = Some(cache.stats());
+        }
+        stats
+    }
+
+    /// Returns an iterator over all caches, the general purpose caches first.
+    fn caches(&self) -> impl Iterator<Item = &SlabCache> {
+        self.size_caches.iter().chain(self.named_caches.iter().flatten())
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<SlabAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        match size_class(layout) {
+            Some(index) => {
+                let SlabAllocator { size_caches, fallback_allocator, .. } = &mut *allocator;
+                size_caches[index].alloc(fallback_allocator)
+            }
+            None => allocator.large_alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        match size_class(layout) {
+            Some(index) => {
+                let SlabAllocator { size_caches, fallback_allocator, .. } = &mut *allocator;
+                size_caches[index].dealloc(ptr, fallback_allocator)
+            }
+            None => {
+                let ptr = NonNull::new(ptr).unwrap();
+                allocator.fallback_allocator.deallocate(ptr, layout);
+                allocator.large_allocated -= layout.size();
+                allocator.large_deallocations += 1;
+            }
+        }
+    }
+}
+
+impl Locked<SlabAllocator> {
+    /// Creates a named cache for objects with the given layout.
+    pub fn create_cache(&self, name: &'static str, layout: Layout) -> Option<CacheId> {
+        self.lock().create_cache(name, layout)
+    }
+
+    /// Removes the given named cache, see `SlabAllocator::destroy_cache`.
+    pub fn destroy_cache(&self, id: CacheId) -> bool {
+        self.lock().destroy_cache(id)
+    }
+
+    /// Returns the statistics of the given named cache.
+    pub fn cache_stats(&self, id: CacheId) -> Option<SlabCacheStats> {
+        self.lock().cache_stats(id)
+    }
+
+    /// Prints the statistics of all caches over the serial interface.
+    pub fn dump_cache_stats(&self) {
+        // take a snapshot first so that the lock isn't held while printing
+        let stats = self.lock().all_cache_stats();
+        for cache in stats.iter().flatten() {
+            cache.dump();
+        }
+    }
+}
+
+impl HeapStatistics for SlabAllocator {
+    fn stats(&mut self) -> HeapStats {
+        let mut stats = HeapStats {
+            heap_size: self.fallback_allocator.size(),
+            bytes_allocated: self.large_allocated,
+            allocations: self.large_allocations,
+            deallocations: self.large_deallocations,
+            ..HeapStats::default()
+        };
+        let mut slab_bytes = 0;
+        let mut free_object_bytes = 0;
+        let mut largest_free_object = 0;
+        for cache in self.caches() {
+            let free_objects = cache.slabs * cache.objects_per_slab - cache.objects_in_use;
+            stats.bytes_allocated += cache.objects_in_use * cache.object_size;
+            stats.allocations += cache.allocations;
+            stats.deallocations += cache.deallocations;
+            slab_bytes += cache.slabs * SLAB_SIZE;
+            free_object_bytes += free_objects * cache.object_size;
+            if free_objects > 0 {
+                largest_free_object = largest_free_object.max(cache.object_size);
+            }
+        }
+
+        let fallback_free = stats.heap_size - self.large_allocated - slab_bytes;
+        stats.bytes_free = fallback_free + free_object_bytes;
+        // an upper bound, see `HeapStats::largest_free_region`
+        stats.largest_free_region = fallback_free.max(largest_free_object);
+        stats
+    }
+}
+
+/// A typed handle to a named cache of the given allocator.
+///
+/// The cache is destroyed when the handle is dropped.
+pub struct ObjectCache<T: 'static> {
+    allocator: &'static Locked<SlabAllocator>,
+    id: CacheId,
+    _type: PhantomData<T>,
+}
+
+impl<T> ObjectCache<T> {
+    /// Creates a new named cache for objects of type `T`.
+    ///
+    /// Returns `None` if `T` is too large for slabs or if all cache slots are
+    /// in use.
+    pub fn new(allocator: &'static Locked<SlabAllocator>, name: &'static str) -> Option<Self> {
+        let id = allocator.create_cache(name, Layout::new::<T>())?;
+        Some(ObjectCache {
+            allocator,
+            id,
+            _type: PhantomData,
+        })
+    }
+
+    /// Moves the given value into an object of the cache.
+    ///
+    /// Returns `None` if the heap is exhausted.
+    pub fn alloc(&self, value: T) -> Option<SlabBox<T>> {
+        let object = NonNull::new(self.allocator.lock().alloc_object(self.id))?.cast::<T>();
+        unsafe { object.as_ptr().write(value) };
+        Some(SlabBox {
+            object,
+            cache: self,
+        })
+    }
+
+    /// Returns the statistics of the cache.
+    pub fn stats(&self) -> SlabCacheStats {
+        self.allocator.cache_stats(self.id).expect("cache exists")
+    }
+}
+
+impl<T> Drop for ObjectCache<T> {
+    fn drop(&mut self) {
+        // the boxes borrow the cache, so objects are only left if a box was
+        // leaked; the cache must stay alive for them then, which leaks it
+        let destroyed = self.allocator.destroy_cache(self.id);
+        debug_assert!(destroyed, "object cache dropped with leaked objects");
+    }
+}
+
+/// An object that was allocated from an `ObjectCache`.
+///
+/// The object is dropped and returned to its cache when the box is dropped.
+pub struct SlabBox<'a, T: 'static> {
+    object: NonNull<T>,
+    cache: &'a ObjectCache<T>,
+}
+
+impl<T> Deref for SlabBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.object.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.object.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.object.as_ptr());
+            self.cache
+                .allocator
+                .lock()
+                .free_object(self.cache.id, self.object.as_ptr() as *mut u8);
+        }
+    }
+}
+
+/// Choose the general purpose cache for the given layout.
+///
+/// Returns an index into the `SIZE_CLASSES` array.
+fn size_class(layout: Layout) -> Option<usize> {
+    let required_size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&s| s >= required_size)
+}
+
+fn slab_layout() -> Layout {
+    Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap()
+}
+
+/// Returns the header of the slab that starts at the given address.
+///
+/// This function is unsafe because the caller must guarantee that a slab of
+/// a cache starts at the address and that no other reference to its header
+/// is used at the same time.
+unsafe fn slab_at(addr: usize) -> &'static mut Slab {
+    &mut *(addr as *mut Slab)
+}
+
+/// Pushes the slab at the given address to the front of the given list.
+///
+/// This function is unsafe because the slab must not be in any list.
+unsafe fn push(list: &mut Option<usize>, addr: usize) {
+    let next = *list;
+    let slab = slab_at(addr);
+    slab.prev = None;
+    slab.next = next;
+    if let Some(next) = next {
+        slab_at(next).prev = Some(addr);
+    }
+    *list = Some(addr);
+}
+
+/// Removes the slab at the given address from the given list.
+///
+/// This function is unsafe because the slab must be in the list.
+unsafe fn unlink(list: &mut Option<usize>, addr: usize) {
+    let slab = slab_at(addr);
+    let (next, prev) = (slab.next.take(), slab.prev.take());
+    match prev {
+        Some(prev) => slab_at(prev).next = next,
+        None => *list = next,
+    }
+    if let Some(next) = next {
+        slab_at(next).prev = prev;
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[cfg(test)]
+use super::TEST_HEAP_SIZE;
+
+#[test_case]
+fn test_one_empty_slab_is_kept() {
+    serial_print!("test_one_empty_slab_is_kept... ");
+    static mut PTRS: [*mut u8; 512] = [ptr::null_mut(); 512];
+    let allocator = test_allocator!(SlabAllocator);
+    let ptrs = unsafe { &mut PTRS };
+
+    // enough 16 byte objects to fill more than one slab
+    let layout = Layout::from_size_align(16, 16).unwrap();
+    for ptr in ptrs.iter_mut() {
+        *ptr = unsafe { allocator.alloc(layout) };
+        assert!(!ptr.is_null());
+        assert_eq!(*ptr as usize % 16, 0);
+    }
+    assert!(allocator.lock().size_caches[0].slabs > 1);
+
+    for &ptr in ptrs.iter() {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+    let stats = allocator.stats();
+    let objects_per_slab = allocator.lock().size_caches[0].objects_per_slab;
+    assert_eq!(allocator.lock().size_caches[0].slabs, 1);
+    assert_eq!(stats.bytes_allocated, 0);
+    assert_eq!(
+        stats.bytes_free,
+        TEST_HEAP_SIZE - SLAB_SIZE + objects_per_slab * 16
+    );
+
+    // the kept slab is reused instead of creating a new one
+    for ptr in ptrs[..objects_per_slab].iter_mut() {
+        *ptr = unsafe { allocator.alloc(layout) };
+        assert!(!ptr.is_null());
+    }
+    assert_eq!(allocator.lock().size_caches[0].slabs, 1);
+    for &ptr in ptrs[..objects_per_slab].iter() {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+    assert_eq!(allocator.lock().size_caches[0].slabs, 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_named_cache() {
+    serial_print!("test_named_cache... ");
+    let allocator = test_allocator!(SlabAllocator);
+
+    let layout = Layout::from_size_align(40, 8).unwrap();
+    let id = allocator.create_cache("test-objects", layout).unwrap();
+    let a = allocator.lock().alloc_object(id);
+    let b = allocator.lock().alloc_object(id);
+    assert!(!a.is_null() && !b.is_null());
+    assert_eq!((b as usize).wrapping_sub(a as usize), 40);
+
+    let stats = allocator.cache_stats(id).unwrap();
+    assert_eq!(stats.name, "test-objects");
+    assert_eq!(stats.objects_in_use, 2);
+    assert_eq!(stats.slabs, 1);
+
+    unsafe {
+        allocator.lock().free_object(id, a);
+        allocator.lock().free_object(id, b);
+    }
+    let stats = allocator.cache_stats(id).unwrap();
+    assert_eq!(stats.objects_in_use, 0);
+    assert_eq!(stats.slabs, 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_object_cache() {
+    serial_print!("test_object_cache... ");
+    let allocator = test_allocator!(SlabAllocator);
+
+    let cache = ObjectCache::<[u64; 3]>::new(allocator, "test-arrays").unwrap();
+    let mut object = cache.alloc([1, 2, 3]).unwrap();
+    object[1] = 42;
+    assert_eq!(*object, [1, 42, 3]);
+    assert_eq!(cache.stats().objects_in_use, 1);
+    drop(object);
+    assert_eq!(cache.stats().objects_in_use, 0);
+    drop(cache);
+
+    // dropping a cache frees its slot
+    for _ in 0..2 * MAX_NAMED_CACHES {
+        let cache = ObjectCache::<u64>::new(allocator, "test-temporary").unwrap();
+        assert!(cache.alloc(7).is_some());
+    }
+    serial_println!("[ok]");
+}
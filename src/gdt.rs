@@ -1,30 +1,36 @@
+use crate::memory::{stack::StackBounds, vmm::VmmError};
 use lazy_static::lazy_static;
+use spin::Mutex;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Size4KiB};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
-}
+/// The size of the guarded double fault stack that `init_stacks` maps.
+pub const DOUBLE_FAULT_STACK_SIZE: u64 = 5 * 4096;
+
+/// The double fault stack until `init_stacks` is called.
+///
+/// It has no guard page, so it is only used during early boot.
+const BOOT_STACK_SIZE: usize = 4096;
+static mut BOOT_DOUBLE_FAULT_STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
+
+/// The task state segment. It is mutable so that `init_stacks` can replace the
+/// boot stack after the TSS is loaded; the CPU reads the stack table on every
+/// interrupt.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// The stack that `init_stacks` installs, kept alive for as long as the TSS
+/// points to it.
+static DOUBLE_FAULT_STACK: Mutex<Option<StackBounds>> = Mutex::new(None);
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &TSS }));
         (
             gdt,
             Selectors {
@@ -44,9 +50,37 @@ pub fn init() {
     use x86_64::instructions::segmentation::set_cs;
     use x86_64::instructions::tables::load_tss;
 
+    unsafe {
+        let stack_start = VirtAddr::from_ptr(&BOOT_DOUBLE_FAULT_STACK);
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_start + BOOT_STACK_SIZE;
+    }
+
     GDT.0.load();
     unsafe {
         set_cs(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Replaces the boot double fault stack with a stack that has a guard page.
+///
+/// Must be called after `init` once paging is set up.
+pub fn init_stacks(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), VmmError> {
+    use x86_64::instructions::interrupts;
+
+    let stack = StackBounds::alloc_with(
+        "double fault stack",
+        DOUBLE_FAULT_STACK_SIZE,
+        mapper,
+        frame_allocator,
+    )?;
+    interrupts::without_interrupts(|| unsafe {
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack.end();
+    });
+    let previous = DOUBLE_FAULT_STACK.lock().replace(stack);
+    assert!(previous.is_none(), "init_stacks was called twice");
+    Ok(())
+}
@@ -1,44 +1,60 @@
-use crate::{gdt, hlt_loop, print, println};
+use crate::{gdt, hlt_loop, memory, println};
 use lazy_static::lazy_static;
 use pic8259_simple::ChainedPics;
 use spin;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
+pub mod apic;
+pub mod exceptions;
+pub mod irq;
+pub mod pit;
+
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
-}
-
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
-    }
-
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
-    }
-}
-
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(exceptions::divide_error_handler);
+        idt.debug.set_handler_fn(exceptions::debug_handler);
+        idt.non_maskable_interrupt
+            .set_handler_fn(exceptions::non_maskable_interrupt_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.overflow.set_handler_fn(exceptions::overflow_handler);
+        idt.bound_range_exceeded
+            .set_handler_fn(exceptions::bound_range_exceeded_handler);
+        idt.invalid_opcode.set_handler_fn(exceptions::invalid_opcode_handler);
+        idt.device_not_available
+            .set_handler_fn(exceptions::device_not_available_handler);
+        idt.invalid_tss.set_handler_fn(exceptions::invalid_tss_handler);
+        idt.segment_not_present
+            .set_handler_fn(exceptions::segment_not_present_handler);
+        idt.stack_segment_fault
+            .set_handler_fn(exceptions::stack_segment_fault_handler);
+        idt.general_protection_fault
+            .set_handler_fn(exceptions::general_protection_fault_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.x87_floating_point
+            .set_handler_fn(exceptions::x87_floating_point_handler);
+        idt.alignment_check.set_handler_fn(exceptions::alignment_check_handler);
+        idt.machine_check.set_handler_fn(exceptions::machine_check_handler);
+        idt.simd_floating_point
+            .set_handler_fn(exceptions::simd_floating_point_handler);
+        idt.virtualization.set_handler_fn(exceptions::virtualization_handler);
+        idt.security_exception
+            .set_handler_fn(exceptions::security_exception_handler);
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        for (irq, &stub) in irq::STUBS.iter().enumerate() {
+            idt[usize::from(PIC_1_OFFSET) + irq].set_handler_fn(stub);
+        }
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
@@ -57,58 +73,82 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
+    let addr = Cr2::read();
+    if memory::cow::handle_page_fault(addr, error_code) {
+        // the page has its own writable frame now
+        return;
+    }
+    if memory::lazy::handle_page_fault(addr, error_code) {
+        // the page is mapped now, so the faulting instruction can be retried
+        return;
+    }
+
     println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
+    if let Some(guard) = memory::guard::find(addr) {
+        println!("Guard page hit: {} {}", guard.owner, guard.violation());
+    }
+    println!("Accessed Address: {:?}", addr);
+    println!("Reason: {}", fault_reason(error_code));
     println!("Error Code: {:?}", error_code);
     println!("{:#?}", stack_frame);
     hlt_loop();
 }
 
+/// Describes the access that caused a page fault.
+fn fault_reason(error_code: PageFaultErrorCode) -> &'static str {
+    let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+    let fetch = error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH);
+    if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+        "reserved bit set in a page table entry"
+    } else if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        if fetch {
+            "protection violation: execute of a non-executable page"
+        } else if write {
+            "protection violation: write to a read-only page"
+        } else {
+            "protection violation: read of a protected page"
+        }
+    } else if fetch {
+        "instruction fetch from a page that is not present"
+    } else if write {
+        "write to a page that is not present"
+    } else {
+        "read of a page that is not present"
+    }
+}
+
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: &mut InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
+    use x86_64::registers::control::Cr2;
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    print!(".");
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+    // a stack overflow causes a page fault that can't be delivered on the
+    // overflowed stack, so check whether the last fault hit a guard page
+    match memory::guard::find(Cr2::read()) {
+        Some(guard) => panic!(
+            "EXCEPTION: DOUBLE FAULT\nGuard page hit: {} {}\n{:#?}",
+            guard.owner,
+            guard.violation(),
+            stack_frame
+        ),
+        None => panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame),
     }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    // use pc_keyboard::{layouts, DecodedKey, Keyboard, ScancodeSet1};
-    // use spin::Mutex;
-    use x86_64::instructions::port::Port;
-
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    crate::task::keyboard::add_scancode(scancode);
-
-    // lazy_static! {
-    //     static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-    //         Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1));
-    // }
-    //
-    // let mut keyboard = KEYBOARD.lock();
-    // let mut port = Port::new(0x60);
-    //
-    // let scancode: u8 = unsafe { port.read() };
-    // if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-    //     if let Some(key) = keyboard.process_keyevent(key_event) {
-    //         match key {
-    //             DecodedKey::Unicode(character) => print!("{}", character),
-    //             DecodedKey::RawKey(key) => print!("{:?}", key),
-    //         }
-    //     }
-    // }
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    // spurious interrupts of the local APIC must not be acknowledged
+}
+
+/// Signals the end of the interrupt with the given vector to the active
+/// interrupt controller.
+fn end_of_interrupt(vector: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(vector);
+        }
     }
 }
 
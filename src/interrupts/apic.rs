@@ -0,0 +1,321 @@
+use super::{irq, pit};
+use crate::acpi::{self, Madt};
+use crate::memory::mmio::{CacheMode, MmioRegion};
+use crate::memory::vmm::VmmError;
+use crate::time::{self, Duration};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+/// The vector that the local APIC uses for spurious interrupts.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const LAPIC_SIZE: u64 = 0x400;
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_EOI: u64 = 0xb0;
+const LAPIC_SPURIOUS: u64 = 0xf0;
+const LAPIC_LVT_TIMER: u64 = 0x320;
+const LAPIC_TIMER_INITIAL_COUNT: u64 = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: u64 = 0x390;
+const LAPIC_TIMER_DIVIDE: u64 = 0x3e0;
+
+/// Enables the local APIC in the spurious interrupt vector register.
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Divides the bus clock by 16 for the timer.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+/// How long `init` measures the APIC timer rate.
+const TIMER_CALIBRATION: Duration = Duration::from_millis(10);
+
+const IOAPIC_SIZE: u64 = 0x20;
+const IOAPIC_REGISTER_SELECT: u64 = 0x00;
+const IOAPIC_WINDOW: u64 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// The model specific register with the local APIC's base address.
+const IA32_APIC_BASE: u32 = 0x1b;
+/// Whether the local APIC is enabled in `IA32_APIC_BASE`.
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+/// Whether the processor has a local APIC in `CPUID.01h:EDX`.
+const CPUID_EDX_APIC: u32 = 1 << 9;
+
+/// Whether the APIC replaced the 8259 PICs.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The virtual address of the local APIC's EOI register, so that interrupt
+/// handlers can signal the end of an interrupt without taking a lock.
+static EOI_REGISTER: AtomicU64 = AtomicU64::new(0);
+
+static LOCAL_APIC: Mutex<Option<LocalApic>> = Mutex::new(None);
+static ROUTING: Mutex<Option<IrqRouting>> = Mutex::new(None);
+
+#[derive(Debug)]
+pub enum ApicError {
+    /// The processor has no local APIC, or it is disabled.
+    NotPresent,
+    /// The ACPI tables have no MADT.
+    NoMadt,
+    /// The MADT doesn't describe an I/O APIC.
+    NoIoApic,
+    /// The registers couldn't be mapped.
+    MapFailed(VmmError),
+}
+
+impl From<VmmError> for ApicError {
+    fn from(err: VmmError) -> Self {
+        ApicError::MapFailed(err)
+    }
+}
+
+/// The local APIC of the current processor.
+struct LocalApic {
+    registers: MmioRegion,
+}
+
+impl LocalApic {
+    fn read(&self, register: u64) -> u32 {
+        self.registers.read(register)
+    }
+
+    fn write(&self, register: u64, value: u32) {
+        self.registers.write(register, value)
+    }
+
+    fn id(&self) -> u8 {
+        (self.read(LAPIC_ID) >> 24) as u8
+    }
+
+    /// Starts the timer in periodic mode with the given vector so that it
+    /// fires at the rate of the PIT.
+    ///
+    /// The timer rate is measured against `time::delay`, so the TSC must be
+    /// calibrated.
+    fn start_timer(&self, vector: u8) {
+        self.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(LAPIC_LVT_TIMER, LVT_MASKED);
+        self.write(LAPIC_TIMER_INITIAL_COUNT, u32::max_value());
+        time::delay(TIMER_CALIBRATION);
+        let elapsed = u32::max_value() - self.read(LAPIC_TIMER_CURRENT_COUNT);
+
+        let count = u128::from(elapsed) * u128::from(pit::tick_nanos())
+            / TIMER_CALIBRATION.as_nanos();
+        self.write(LAPIC_LVT_TIMER, LVT_TIMER_PERIODIC | u32::from(vector));
+        self.write(LAPIC_TIMER_INITIAL_COUNT, count.max(1) as u32);
+    }
+}
+
+/// An I/O APIC, which routes global system interrupts to local APICs.
+struct IoApic {
+    registers: MmioRegion,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        self.registers.write(IOAPIC_REGISTER_SELECT, register);
+        self.registers.read(IOAPIC_WINDOW)
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        self.registers.write(IOAPIC_REGISTER_SELECT, register);
+        self.registers.write(IOAPIC_WINDOW, value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        // mask the entry while it is inconsistent
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+
+    fn mask_all(&self) {
+        for gsi in self.gsi_base..self.gsi_base + self.entries {
+            self.set_redirection(gsi, REDIRECTION_MASKED);
+        }
+    }
+}
+
+/// The I/O APICs and what is needed to route ISA IRQs through them.
+struct IrqRouting {
+    io_apics: Vec<IoApic>,
+    madt: Madt,
+    /// The local APIC ID of the processor that handles the IRQs.
+    destination: u8,
+}
+
+impl IrqRouting {
+    fn set_enabled(&self, irq: u8, enabled: bool) {
+        if !self.is_connected(irq) {
+            return;
+        }
+        let isa_irq = self.madt.isa_irq(irq);
+        let mut entry = u64::from(self.destination) << 56 | u64::from(irq::vector(irq));
+        if isa_irq.active_low {
+            entry |= REDIRECTION_ACTIVE_LOW;
+        }
+        if isa_irq.level_triggered {
+            entry |= REDIRECTION_LEVEL_TRIGGERED;
+        }
+        if !enabled {
+            entry |= REDIRECTION_MASKED;
+        }
+        if let Some(io_apic) = self.io_apics.iter().find(|io_apic| io_apic.handles(isa_irq.gsi)) {
+            io_apic.set_redirection(isa_irq.gsi, entry);
+        }
+    }
+
+    /// Returns whether the given ISA IRQ has an override in the MADT.
+    fn has_override(&self, irq: u8) -> bool {
+        self.madt.overrides.iter().any(|o| o.irq == irq)
+    }
+
+    /// Returns false if another ISA IRQ is redirected to the global system
+    /// interrupt of the given one, like IRQ 0 to GSI 2 on most systems.
+    fn is_connected(&self, irq: u8) -> bool {
+        let gsi = self.madt.isa_irq(irq).gsi;
+        !self.madt.overrides.iter().any(|o| o.irq != irq && o.gsi == gsi)
+    }
+}
+
+/// Returns whether the processor has a local APIC that is enabled.
+fn is_present() -> bool {
+    use core::arch::x86_64::__cpuid;
+    use x86_64::registers::model_specific::Msr;
+
+    // CPUID leaf 1 is available on every x86_64 processor
+    let features = unsafe { __cpuid(1) };
+    if features.edx & CPUID_EDX_APIC == 0 {
+        return false;
+    }
+    // the MSR exists if CPUID reports an APIC
+    let apic_base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    apic_base & APIC_BASE_GLOBAL_ENABLE != 0
+}
+
+/// Replaces the 8259 PICs with the local APIC and the I/O APICs.
+///
+/// Fails with `NotPresent` before touching the MADT or the PICs if the
+/// processor has no usable local APIC.
+///
+/// Routes the IRQs that have handlers through the I/O APIC and drives the
+/// timer interrupt by the local APIC timer at the rate of the PIT, which is not
+/// routed anymore. Needs the memory globals for the register mappings and a
+/// calibrated TSC, see `time::calibrate`.
+pub fn init() -> Result<(), ApicError> {
+    use x86_64::instructions::interrupts;
+
+    if !is_present() {
+        return Err(ApicError::NotPresent);
+    }
+    let madt = acpi::madt().ok_or(ApicError::NoMadt)?;
+
+    // the registers are device memory, not RAM managed by the frame allocator
+    let local_apic_address = PhysAddr::new(madt.local_apic_address);
+    let local_apic = LocalApic {
+        registers: unsafe {
+            MmioRegion::map(local_apic_address, LAPIC_SIZE, CacheMode::Uncached)?
+        },
+    };
+    let mut io_apics = Vec::new();
+    for info in madt.io_apics.iter() {
+        let registers = unsafe {
+            MmioRegion::map(PhysAddr::new(info.address), IOAPIC_SIZE, CacheMode::Uncached)?
+        };
+        let mut io_apic = IoApic {
+            registers,
+            gsi_base: info.gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        io_apics.push(io_apic);
+    }
+
+    if io_apics.is_empty() {
+        return Err(ApicError::NoIoApic);
+    }
+
+    interrupts::without_interrupts(|| {
+        disable_pics();
+        for io_apic in io_apics.iter() {
+            io_apic.mask_all();
+        }
+
+        local_apic.write(LAPIC_SPURIOUS, LAPIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+        let routing = IrqRouting {
+            io_apics,
+            madt,
+            destination: local_apic.id(),
+        };
+        // the local APIC timer replaces the timer IRQ; lines with overrides
+        // are programmed with their polarity and trigger mode even without
+        // handlers, so that they stay masked in a defined state
+        let routed = (0..irq::IRQ_COUNT as u8).filter(|&irq| {
+            irq != irq::TIMER && (irq::has_handlers(irq) || routing.has_override(irq))
+        });
+        for irq in routed {
+            routing.set_enabled(irq, irq::has_handlers(irq));
+        }
+        // `irq::register` routes lines through the I/O APIC from now on
+        *ROUTING.lock() = Some(routing);
+
+        let eoi_register = local_apic.registers.base() + LAPIC_EOI;
+        EOI_REGISTER.store(eoi_register.as_u64(), Ordering::Relaxed);
+        ENABLED.store(true, Ordering::Relaxed);
+    });
+
+    // no ticks arrive until the timer runs, which takes `TIMER_CALIBRATION`
+    local_apic.start_timer(irq::vector(irq::TIMER));
+
+    *LOCAL_APIC.lock() = Some(local_apic);
+    Ok(())
+}
+
+/// Returns whether `init` replaced the 8259 PICs.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Unmasks or masks the given ISA IRQ in the I/O APIC.
+///
+/// The timer IRQ is never routed, since the local APIC timer replaces it.
+pub(super) fn set_irq_enabled(irq: u8, enabled: bool) {
+    if irq == irq::TIMER {
+        return;
+    }
+    if let Some(routing) = ROUTING.lock().as_ref() {
+        routing.set_enabled(irq, enabled);
+    }
+}
+
+/// Signals the end of an interrupt to the local APIC.
+///
+/// Must only be called if `is_enabled` returns true.
+pub(super) fn end_of_interrupt() {
+    let register = EOI_REGISTER.load(Ordering::Relaxed) as *mut u32;
+    // the register stays mapped as long as `LOCAL_APIC` holds the mapping
+    unsafe { register.write_volatile(0) };
+}
+
+/// Masks all IRQs of both 8259 PICs.
+fn disable_pics() {
+    let mut primary_data = Port::<u8>::new(0x21);
+    let mut secondary_data = Port::<u8>::new(0xa1);
+    unsafe {
+        primary_data.write(0xff);
+        secondary_data.write(0xff);
+    }
+}
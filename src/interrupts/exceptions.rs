@@ -0,0 +1,440 @@
+use crate::serial::SERIAL1;
+use core::fmt::{self, Write};
+use x86_64::structures::idt::{InterruptStackFrame, InterruptStackFrameValue};
+
+/// The architectural exceptions that the IDT has handlers for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Exception {
+    DivideError = 0,
+    Debug = 1,
+    NonMaskableInterrupt = 2,
+    Breakpoint = 3,
+    Overflow = 4,
+    BoundRangeExceeded = 5,
+    InvalidOpcode = 6,
+    DeviceNotAvailable = 7,
+    DoubleFault = 8,
+    InvalidTss = 10,
+    SegmentNotPresent = 11,
+    StackSegmentFault = 12,
+    GeneralProtectionFault = 13,
+    PageFault = 14,
+    X87FloatingPoint = 16,
+    AlignmentCheck = 17,
+    MachineCheck = 18,
+    SimdFloatingPoint = 19,
+    Virtualization = 20,
+    SecurityException = 30,
+}
+
+impl Exception {
+    pub fn vector(self) -> u8 {
+        self as u8
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Exception::DivideError => "DIVIDE ERROR",
+            Exception::Debug => "DEBUG",
+            Exception::NonMaskableInterrupt => "NON-MASKABLE INTERRUPT",
+            Exception::Breakpoint => "BREAKPOINT",
+            Exception::Overflow => "OVERFLOW",
+            Exception::BoundRangeExceeded => "BOUND RANGE EXCEEDED",
+            Exception::InvalidOpcode => "INVALID OPCODE",
+            Exception::DeviceNotAvailable => "DEVICE NOT AVAILABLE",
+            Exception::DoubleFault => "DOUBLE FAULT",
+            Exception::InvalidTss => "INVALID TSS",
+            Exception::SegmentNotPresent => "SEGMENT NOT PRESENT",
+            Exception::StackSegmentFault => "STACK-SEGMENT FAULT",
+            Exception::GeneralProtectionFault => "GENERAL PROTECTION FAULT",
+            Exception::PageFault => "PAGE FAULT",
+            Exception::X87FloatingPoint => "X87 FLOATING-POINT EXCEPTION",
+            Exception::AlignmentCheck => "ALIGNMENT CHECK",
+            Exception::MachineCheck => "MACHINE CHECK",
+            Exception::SimdFloatingPoint => "SIMD FLOATING-POINT EXCEPTION",
+            Exception::Virtualization => "VIRTUALIZATION EXCEPTION",
+            Exception::SecurityException => "SECURITY EXCEPTION",
+        }
+    }
+
+    /// Returns the short name that the Intel manuals use, e.g. `#GP`.
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Exception::DivideError => "#DE",
+            Exception::Debug => "#DB",
+            Exception::NonMaskableInterrupt => "NMI",
+            Exception::Breakpoint => "#BP",
+            Exception::Overflow => "#OF",
+            Exception::BoundRangeExceeded => "#BR",
+            Exception::InvalidOpcode => "#UD",
+            Exception::DeviceNotAvailable => "#NM",
+            Exception::DoubleFault => "#DF",
+            Exception::InvalidTss => "#TS",
+            Exception::SegmentNotPresent => "#NP",
+            Exception::StackSegmentFault => "#SS",
+            Exception::GeneralProtectionFault => "#GP",
+            Exception::PageFault => "#PF",
+            Exception::X87FloatingPoint => "#MF",
+            Exception::AlignmentCheck => "#AC",
+            Exception::MachineCheck => "#MC",
+            Exception::SimdFloatingPoint => "#XM",
+            Exception::Virtualization => "#VE",
+            Exception::SecurityException => "#SX",
+        }
+    }
+
+    /// Returns whether the error code of the exception refers to a segment
+    /// selector or an IDT entry.
+    pub fn has_selector_error_code(self) -> bool {
+        match self {
+            Exception::InvalidTss
+            | Exception::SegmentNotPresent
+            | Exception::StackSegmentFault
+            | Exception::GeneralProtectionFault => true,
+            _ => false,
+        }
+    }
+}
+
+/// The descriptor table that a selector error code refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+impl fmt::Display for DescriptorTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DescriptorTable::Gdt => "GDT",
+            DescriptorTable::Idt => "IDT",
+            DescriptorTable::Ldt => "LDT",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The error code of the exceptions that are caused by a segment selector or
+/// an interrupt vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(u64);
+
+impl SelectorErrorCode {
+    pub fn new(error_code: u64) -> Self {
+        SelectorErrorCode(error_code)
+    }
+
+    /// Returns whether the exception happened while delivering an external
+    /// interrupt or an earlier exception.
+    pub fn is_external(&self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    pub fn table(&self) -> DescriptorTable {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b10 => DescriptorTable::Ldt,
+            _ => DescriptorTable::Idt,
+        }
+    }
+
+    /// Returns the index of the descriptor, which is the vector for the IDT.
+    pub fn index(&self) -> u16 {
+        ((self.0 >> 3) & 0x1fff) as u16
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "index {} in the {}", self.index(), self.table())?;
+        if self.is_external() {
+            write!(f, ", external")?;
+        }
+        Ok(())
+    }
+}
+
+/// What the exception handlers print about an exception.
+pub struct ExceptionReport<'a> {
+    pub exception: Exception,
+    pub error_code: Option<u64>,
+    pub stack_frame: &'a InterruptStackFrameValue,
+}
+
+impl fmt::Display for ExceptionReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let exception = self.exception;
+        writeln!(
+            f,
+            "EXCEPTION: {} ({}, vector {})",
+            exception.name(),
+            exception.mnemonic(),
+            exception.vector()
+        )?;
+        if let Some(error_code) = self.error_code {
+            write!(f, "Error Code: {:#x}", error_code)?;
+            // a zero error code isn't caused by a selector
+            if exception.has_selector_error_code() && error_code != 0 {
+                write!(f, " ({})", SelectorErrorCode::new(error_code))?;
+            }
+            writeln!(f)?;
+        }
+        write!(f, "{:#?}", self.stack_frame)
+    }
+}
+
+/// Reports an exception after which execution continues over the serial
+/// interface.
+///
+/// Debug exceptions and NMIs can interrupt code that holds the serial or VGA
+/// lock even with interrupts disabled, so the report is skipped if the serial
+/// lock is taken instead of waiting for it forever.
+fn report(exception: Exception, stack_frame: &InterruptStackFrame) {
+    let report = ExceptionReport {
+        exception,
+        error_code: None,
+        stack_frame,
+    };
+    if let Some(mut serial) = SERIAL1.try_lock() {
+        let _ = writeln!(serial, "{}", report);
+    }
+}
+
+/// Reports an exception that the kernel can't recover from and panics.
+fn fatal(exception: Exception, error_code: Option<u64>, stack_frame: &InterruptStackFrame) -> ! {
+    let report = ExceptionReport {
+        exception,
+        error_code,
+        stack_frame,
+    };
+    panic!("{}", report)
+}
+
+pub(super) extern "x86-interrupt" fn divide_error_handler(stack_frame: &mut InterruptStackFrame) {
+    fatal(Exception::DivideError, None, stack_frame)
+}
+
+pub(super) extern "x86-interrupt" fn debug_handler(stack_frame: &mut InterruptStackFrame) {
+    // debug exceptions are traps, so execution can continue
+    report(Exception::Debug, stack_frame);
+}
+
+pub(super) extern "x86-interrupt" fn non_maskable_interrupt_handler(
+    stack_frame: &mut InterruptStackFrame,
+) {
+    report(Exception::NonMaskableInterrupt, stack_frame);
+}
+
+pub(super) extern "x86-interrupt" fn overflow_handler(stack_frame: &mut InterruptStackFrame) {
+    fatal(Exception::Overflow, None, stack_frame)
+}
+
+pub(super) extern "x86-interrupt" fn bound_range_exceeded_handler(
+    stack_frame: &mut InterruptStackFrame,
+) {
+    fatal(Exception::BoundRangeExceeded, None, stack_frame)
+}
+
+pub(super) extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: &mut InterruptStackFrame) {
+    fatal(Exception::InvalidOpcode, None, stack_frame)
+}
+
+pub(super) extern "x86-interrupt" fn device_not_available_handler(
+    stack_frame: &mut InterruptStackFrame,
+) {
+    fatal(Exception::DeviceNotAvailable, None, stack_frame)
+}
+
+pub(super) extern "x86-interrupt" fn invalid_tss_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal(Exception::InvalidTss, Some(error_code), stack_frame)
+}
+
+pub(super) extern "x86-interrupt" fn segment_not_present_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal(Exception::SegmentNotPresent, Some(error_code), stack_frame)
+}
+
+pub(super) extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal(Exception::StackSegmentFault, Some(error_code), stack_frame)
+}
+
+pub(super) extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal(Exception::GeneralProtectionFault, Some(error_code), stack_frame)
+}
+
+pub(super) extern "x86-interrupt" fn x87_floating_point_handler(
+    stack_frame: &mut InterruptStackFrame,
+) {
+    fatal(Exception::X87FloatingPoint, None, stack_frame)
+}
+
+pub(super) extern "x86-interrupt" fn alignment_check_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal(Exception::AlignmentCheck, Some(error_code), stack_frame)
+}
+
+pub(super) extern "x86-interrupt" fn machine_check_handler(
+    stack_frame: &mut InterruptStackFrame,
+) -> ! {
+    fatal(Exception::MachineCheck, None, stack_frame)
+}
+
+pub(super) extern "x86-interrupt" fn simd_floating_point_handler(
+    stack_frame: &mut InterruptStackFrame,
+) {
+    fatal(Exception::SimdFloatingPoint, None, stack_frame)
+}
+
+pub(super) extern "x86-interrupt" fn virtualization_handler(stack_frame: &mut InterruptStackFrame) {
+    fatal(Exception::Virtualization, None, stack_frame)
+}
+
+pub(super) extern "x86-interrupt" fn security_exception_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal(Exception::SecurityException, Some(error_code), stack_frame)
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_selector_error_code() {
+    serial_print!("test_selector_error_code... ");
+    // selector 0x28 of the GDT
+    let gdt = SelectorErrorCode::new(0x28);
+    assert_eq!(gdt.table(), DescriptorTable::Gdt);
+    assert_eq!(gdt.index(), 5);
+    assert!(!gdt.is_external());
+    // IDT entry 0x21 while delivering an external interrupt
+    let idt = SelectorErrorCode::new(0x21 << 3 | 0b011);
+    assert_eq!(idt.table(), DescriptorTable::Idt);
+    assert_eq!(idt.index(), 0x21);
+    assert!(idt.is_external());
+    assert_eq!(SelectorErrorCode::new(0b100).table(), DescriptorTable::Ldt);
+    serial_println!("[ok]");
+}
+
+/// Formats a report into a fixed buffer, since the unit tests run without a
+/// heap.
+#[cfg(test)]
+struct ReportBuffer {
+    bytes: [u8; 512],
+    len: usize,
+}
+
+#[cfg(test)]
+impl ReportBuffer {
+    fn format(report: &ExceptionReport) -> Self {
+        let mut buffer = ReportBuffer {
+            bytes: [0; 512],
+            len: 0,
+        };
+        write!(buffer, "{}", report).expect("report doesn't fit into the buffer");
+        buffer
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Write for ReportBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.bytes.len() {
+            return Err(fmt::Error);
+        }
+        self.bytes[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn test_stack_frame() -> InterruptStackFrameValue {
+    use x86_64::VirtAddr;
+
+    InterruptStackFrameValue {
+        instruction_pointer: VirtAddr::new(0x20_1234),
+        code_segment: 0x8,
+        cpu_flags: 0x2,
+        stack_pointer: VirtAddr::new(0x4444_4444_8000),
+        stack_segment: 0,
+    }
+}
+
+// #TS, #AC and #MC can't be raised by the kernel in ring 0, so their reports
+// are only checked here
+
+#[test_case]
+fn test_report_with_selector() {
+    serial_print!("test_report_with_selector... ");
+    let stack_frame = test_stack_frame();
+    let report = ExceptionReport {
+        exception: Exception::InvalidTss,
+        error_code: Some(0x28),
+        stack_frame: &stack_frame,
+    };
+    let buffer = ReportBuffer::format(&report);
+    assert!(buffer.as_str().starts_with(
+        "EXCEPTION: INVALID TSS (#TS, vector 10)\nError Code: 0x28 (index 5 in the GDT)\n"
+    ));
+    assert!(buffer.as_str().contains("instruction_pointer"));
+    assert!(buffer.as_str().contains("0x201234"));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_report_without_selector() {
+    serial_print!("test_report_without_selector... ");
+    let stack_frame = test_stack_frame();
+    // the error code of #AC is always zero and doesn't refer to a selector
+    let report = ExceptionReport {
+        exception: Exception::AlignmentCheck,
+        error_code: Some(0),
+        stack_frame: &stack_frame,
+    };
+    let buffer = ReportBuffer::format(&report);
+    assert!(buffer
+        .as_str()
+        .starts_with("EXCEPTION: ALIGNMENT CHECK (#AC, vector 17)\nError Code: 0x0\n"));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_report_without_error_code() {
+    serial_print!("test_report_without_error_code... ");
+    let stack_frame = test_stack_frame();
+    for &exception in [Exception::MachineCheck, Exception::SimdFloatingPoint].iter() {
+        let report = ExceptionReport {
+            exception,
+            error_code: None,
+            stack_frame: &stack_frame,
+        };
+        let buffer = ReportBuffer::format(&report);
+        let first_line = buffer.as_str().lines().next().unwrap();
+        assert!(first_line.contains(exception.name()));
+        assert!(first_line.contains(exception.mnemonic()));
+        assert!(!buffer.as_str().contains("Error Code"));
+        assert!(buffer.as_str().contains("stack_pointer"));
+    }
+    serial_println!("[ok]");
+}
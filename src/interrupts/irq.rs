@@ -0,0 +1,260 @@
+use super::{apic, PICS, PIC_1_OFFSET};
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+use x86_64::structures::idt::{HandlerFunc, InterruptStackFrame};
+
+/// The number of IRQ lines of the two 8259 PICs. The ISA IRQs that the I/O
+/// APIC routes use the same numbers.
+pub const IRQ_COUNT: usize = 16;
+
+/// How many handlers can share an IRQ line.
+pub const MAX_SHARED_HANDLERS: usize = 4;
+
+pub const TIMER: u8 = 0;
+pub const KEYBOARD: u8 = 1;
+/// The line that connects the secondary PIC to the primary one.
+const CASCADE: u8 = 2;
+pub const COM1: u8 = 4;
+
+const PIC_1_COMMAND_PORT: u16 = 0x20;
+const PIC_1_DATA_PORT: u16 = 0x21;
+const PIC_2_COMMAND_PORT: u16 = 0xa0;
+const PIC_2_DATA_PORT: u16 = 0xa1;
+
+/// The OCW3 command that makes the next read of a PIC's command port return
+/// its in-service register.
+const PIC_READ_ISR: u8 = 0x0b;
+const PIC_END_OF_INTERRUPT: u8 = 0x20;
+
+/// A handler for an IRQ line.
+///
+/// It runs in the interrupt handler, so it must not block or allocate. The end
+/// of the interrupt is signaled after all handlers of the line ran.
+pub type IrqHandler = fn();
+
+/// An empty handler slot.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLER: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLERS: [AtomicUsize; MAX_SHARED_HANDLERS] = [NO_HANDLER; MAX_SHARED_HANDLERS];
+
+/// The registered handlers of each line as `IrqHandler` addresses, or zero for
+/// an empty slot. Atomics instead of a lock let the interrupt handlers read
+/// the table while a driver changes it.
+static HANDLERS: [[AtomicUsize; MAX_SHARED_HANDLERS]; IRQ_COUNT] = [NO_HANDLERS; IRQ_COUNT];
+
+/// Serializes `register` and `unregister`, so that checking the slots of a
+/// line, changing them and enabling or disabling the line happen as one step.
+/// The interrupt handlers read the slots without it.
+static REGISTRATION: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The IRQ doesn't exist or can't have handlers, like the cascade line.
+    InvalidIrq(u8),
+    /// The handler is already registered for the IRQ.
+    AlreadyRegistered(u8),
+    /// The IRQ has `MAX_SHARED_HANDLERS` handlers already.
+    LineFull(u8),
+    /// The handler is not registered for the IRQ.
+    NotRegistered(u8),
+}
+
+/// Returns the interrupt vector of the given IRQ.
+pub fn vector(irq: u8) -> u8 {
+    PIC_1_OFFSET + irq
+}
+
+/// Adds a handler for the given IRQ line and enables the line on the active
+/// interrupt controller.
+///
+/// A line can be shared by up to `MAX_SHARED_HANDLERS` handlers, which run in
+/// the order of their slots on every interrupt of the line.
+pub fn register(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    let line = line(irq)?;
+    let address = handler as usize;
+    interrupts::without_interrupts(|| {
+        let _registration = REGISTRATION.lock();
+        if line.iter().any(|slot| slot.load(Ordering::Acquire) == address) {
+            return Err(IrqError::AlreadyRegistered(irq));
+        }
+        let registered = line.iter().any(|slot| {
+            slot.compare_exchange(0, address, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        });
+        if !registered {
+            return Err(IrqError::LineFull(irq));
+        }
+        set_line_enabled(irq, true);
+        Ok(())
+    })
+}
+
+/// Removes a handler that `register` added, and disables the line if no
+/// handlers remain.
+///
+/// The handler may still run once if its interrupt is being handled on
+/// another processor.
+pub fn unregister(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    let line = line(irq)?;
+    let address = handler as usize;
+    interrupts::without_interrupts(|| {
+        let _registration = REGISTRATION.lock();
+        let unregistered = line.iter().any(|slot| {
+            slot.compare_exchange(address, 0, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        });
+        if !unregistered {
+            return Err(IrqError::NotRegistered(irq));
+        }
+        if !has_handlers(irq) {
+            set_line_enabled(irq, false);
+        }
+        Ok(())
+    })
+}
+
+/// Returns whether any handlers are registered for the given IRQ.
+pub fn has_handlers(irq: u8) -> bool {
+    match line(irq) {
+        Ok(line) => line.iter().any(|slot| slot.load(Ordering::Acquire) != 0),
+        Err(_) => false,
+    }
+}
+
+fn line(irq: u8) -> Result<&'static [AtomicUsize; MAX_SHARED_HANDLERS], IrqError> {
+    if irq == CASCADE {
+        return Err(IrqError::InvalidIrq(irq));
+    }
+    HANDLERS
+        .get(usize::from(irq))
+        .ok_or(IrqError::InvalidIrq(irq))
+}
+
+/// Runs the handlers of the given IRQ and signals the end of the interrupt.
+fn dispatch(irq: u8) {
+    if is_spurious(irq) {
+        return;
+    }
+    for slot in HANDLERS[usize::from(irq)].iter() {
+        let address = slot.load(Ordering::Acquire);
+        if address != 0 {
+            // only `register` stores non-zero values, which are `IrqHandler`s
+            let handler: IrqHandler = unsafe { mem::transmute(address) };
+            handler();
+        }
+    }
+    super::end_of_interrupt(vector(irq));
+}
+
+/// Checks whether the 8259 PICs raised the given IRQ without a line being in
+/// service, which they report on their lowest priority line, 7 or 15.
+///
+/// A spurious IRQ must not be acknowledged at the PIC that raised it. For IRQ
+/// 15 the primary PIC still forwarded it through the cascade line, so only the
+/// primary PIC gets an end of interrupt.
+fn is_spurious(irq: u8) -> bool {
+    if apic::is_enabled() || (irq != 7 && irq != 15) {
+        return false;
+    }
+    let (port, bit) = if irq < 8 {
+        (PIC_1_COMMAND_PORT, irq)
+    } else {
+        (PIC_2_COMMAND_PORT, irq - 8)
+    };
+    let mut command = Port::<u8>::new(port);
+    let in_service = unsafe {
+        command.write(PIC_READ_ISR);
+        command.read()
+    };
+    if in_service & 1 << bit != 0 {
+        return false;
+    }
+    if irq == 15 {
+        unsafe { Port::<u8>::new(PIC_1_COMMAND_PORT).write(PIC_END_OF_INTERRUPT) };
+    }
+    true
+}
+
+fn set_line_enabled(irq: u8, enabled: bool) {
+    if apic::is_enabled() {
+        apic::set_irq_enabled(irq, enabled);
+    } else {
+        set_pic_masked(irq, !enabled);
+    }
+}
+
+/// Masks or unmasks the given line in the 8259 PICs.
+fn set_pic_masked(irq: u8, masked: bool) {
+    let (port, bit) = if irq < 8 {
+        (PIC_1_DATA_PORT, irq)
+    } else {
+        (PIC_2_DATA_PORT, irq - 8)
+    };
+    interrupts::without_interrupts(|| {
+        // `ChainedPics` has no access to the masks, but holding its lock keeps
+        // it from programming the PICs in between
+        let _pics = PICS.lock();
+        let mut data = Port::<u8>::new(port);
+        unsafe {
+            let mask = data.read();
+            if masked {
+                data.write(mask | 1 << bit);
+            } else {
+                data.write(mask & !(1 << bit));
+            }
+        }
+    });
+    if irq >= 8 && !masked {
+        // the secondary PIC only reaches the CPU through the cascade line
+        set_pic_masked(CASCADE, false);
+    }
+}
+
+macro_rules! irq_stubs {
+    ($($irq:expr => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: &mut InterruptStackFrame) {
+                dispatch($irq);
+            }
+        )*
+
+        /// The IDT handlers of the IRQ lines, indexed by IRQ.
+        pub(super) const STUBS: [HandlerFunc; IRQ_COUNT] = [$($name),*];
+    };
+}
+
+irq_stubs! {
+    0 => irq_0,
+    1 => irq_1,
+    2 => irq_2,
+    3 => irq_3,
+    4 => irq_4,
+    5 => irq_5,
+    6 => irq_6,
+    7 => irq_7,
+    8 => irq_8,
+    9 => irq_9,
+    10 => irq_10,
+    11 => irq_11,
+    12 => irq_12,
+    13 => irq_13,
+    14 => irq_14,
+    15 => irq_15,
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_invalid_irqs() {
+    serial_print!("test_invalid_irqs... ");
+    fn handler() {}
+    assert_eq!(register(CASCADE, handler), Err(IrqError::InvalidIrq(CASCADE)));
+    assert_eq!(register(16, handler), Err(IrqError::InvalidIrq(16)));
+    assert_eq!(unregister(3, handler), Err(IrqError::NotRegistered(3)));
+    assert!(!has_handlers(16));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,110 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use x86_64::instructions::port::Port;
+
+/// The frequency of the PIT's input clock in Hz.
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+/// The timer interrupt rate that `crate::init` programs.
+pub const DEFAULT_FREQUENCY: u32 = 100;
+
+const CHANNEL_0_PORT: u16 = 0x40;
+const COMMAND_PORT: u16 = 0x43;
+
+/// Channel 0, lobyte/hibyte access, mode 2 (rate generator), binary counting.
+const CHANNEL_0_RATE_GENERATOR: u8 = 0b0011_0100;
+
+/// The divisor that channel 0 is programmed with. The PIT starts with 65536.
+static DIVISOR: AtomicU32 = AtomicU32::new(65536);
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Programs channel 0 to fire the timer interrupt at about the given rate.
+///
+/// Returns the rate that the PIT actually uses, which differs from the
+/// requested one since it is `BASE_FREQUENCY` divided by an integer between
+/// 1 and 65536.
+///
+/// Panics if called more than once, since the tick counter of `task::timer`
+/// assumes a fixed tick length.
+pub fn init(frequency: u32) -> u32 {
+    use x86_64::instructions::interrupts;
+
+    assert!(
+        !INITIALIZED.swap(true, Ordering::Relaxed),
+        "the PIT was initialized already"
+    );
+    let divisor = divisor_for(frequency);
+    interrupts::without_interrupts(|| {
+        let mut command = Port::<u8>::new(COMMAND_PORT);
+        let mut channel_0 = Port::<u8>::new(CHANNEL_0_PORT);
+        unsafe {
+            command.write(CHANNEL_0_RATE_GENERATOR);
+            // a divisor of 65536 is written as 0
+            channel_0.write(divisor as u8);
+            channel_0.write((divisor >> 8) as u8);
+        }
+        DIVISOR.store(divisor, Ordering::Relaxed);
+    });
+    frequency_for(divisor)
+}
+
+/// Returns the rate of the timer interrupt in Hz, rounded down.
+pub fn frequency() -> u32 {
+    frequency_for(DIVISOR.load(Ordering::Relaxed))
+}
+
+/// Returns the length of a timer tick in nanoseconds, rounded to the nearest
+/// nanosecond.
+pub fn tick_nanos() -> u64 {
+    let divisor = u64::from(DIVISOR.load(Ordering::Relaxed));
+    let base = u64::from(BASE_FREQUENCY);
+    (divisor * 1_000_000_000 + base / 2) / base
+}
+
+fn divisor_for(frequency: u32) -> u32 {
+    assert!(frequency > 0, "timer frequency must not be zero");
+    let divisor = (BASE_FREQUENCY + frequency / 2) / frequency;
+    divisor.max(1).min(65536)
+}
+
+fn frequency_for(divisor: u32) -> u32 {
+    BASE_FREQUENCY / divisor
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_divisor_is_clamped() {
+    serial_print!("test_divisor_is_clamped... ");
+    assert_eq!(divisor_for(1), 65536);
+    assert_eq!(divisor_for(18), 65536);
+    assert_eq!(divisor_for(BASE_FREQUENCY), 1);
+    assert_eq!(divisor_for(u32::max_value()), 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_divisor_is_closest() {
+    serial_print!("test_divisor_is_closest... ");
+    let error = |divisor: u32| (i64::from(BASE_FREQUENCY) - i64::from(divisor) * 100).abs();
+    let divisor = divisor_for(100);
+    assert!(error(divisor) <= error(divisor - 1));
+    assert!(error(divisor) <= error(divisor + 1));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_tick_matches_frequency() {
+    serial_print!("test_tick_matches_frequency... ");
+    let divisor = DIVISOR.load(Ordering::Relaxed);
+    assert_eq!(frequency(), frequency_for(divisor));
+    // `frequency` ticks take at most a second, since the frequency is rounded
+    // down, up to the rounding of the tick length to whole nanoseconds
+    let tick = tick_nanos();
+    let frequency = u64::from(frequency());
+    let error = (frequency * tick) as i64 - 1_000_000_000;
+    assert!(error <= (frequency / 2) as i64);
+    assert!(-error < (tick + frequency) as i64);
+    serial_println!("[ok]");
+}
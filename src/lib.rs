@@ -19,18 +19,31 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
     panic!("allocation error: {:?}", layout)
 }
 
+pub mod acpi;
 pub mod allocator;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
 pub mod serial;
 pub mod task;
+pub mod time;
 pub mod vga_buffer;
 
 pub fn init() {
+    init_with_frequency(interrupts::pit::DEFAULT_FREQUENCY);
+}
+
+/// Like `init`, but fires the timer interrupt at about the given rate.
+///
+/// The TSC is not calibrated; call `time::calibrate` afterwards for precise
+/// timing.
+pub fn init_with_frequency(frequency: u32) {
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    interrupts::pit::init(frequency);
+    task::timer::init();
+    task::keyboard::init();
     x86_64::instructions::interrupts::enable();
 }
 
@@ -81,6 +94,7 @@ entry_point!(test_kernel_main);
 #[cfg(test)]
 fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
     init();
+    time::calibrate();
     test_main();
     hlt_loop();
 }
@@ -15,31 +15,56 @@ use blog_os::task::{Task, executor::Executor, keyboard};
 entry_point!(kernel_main);
 
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
-    use blog_os::memory::{self, BootInfoFrameAllocator};
-    use x86_64::{structures::paging::Page, VirtAddr};
+    use blog_os::memory::{self, bitmap::BitmapFrameAllocator};
+    use blog_os::memory::mmio::{CacheMode, MmioRegion};
+    use x86_64::{PhysAddr, VirtAddr};
 
     println!("Hello World{}", "!");
     blog_os::init();
+    blog_os::time::calibrate();
+
+    memory::physical::report(&boot_info.memory_map);
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
 
     blog_os::allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    blog_os::gdt::init_stacks(&mut mapper, &mut frame_allocator)
+        .expect("stack initialization failed");
+    unsafe { memory::protect::protect_kernel(&boot_info.memory_map, &mut mapper) }
+        .expect("failed to protect the kernel mappings");
+    memory::init_globals(mapper, frame_allocator);
+    blog_os::serial::init_input();
+
+    #[cfg(not(feature = "legacy-pic"))]
+    {
+        if let Err(err) = blog_os::interrupts::apic::init() {
+            println!("APIC unavailable ({:?}), using the 8259 PICs", err);
+        }
+    }
+    println!(
+        "{} KiB of {} KiB physical memory free",
+        memory::free_memory() / 1024,
+        memory::total_memory() / 1024
+    );
 
     let mut executor = Executor::new();
     executor.spawn(Task::new(example_task()));
     executor.spawn(Task::new(keyboard::print_keypresses()));
     executor.run();
 
-    // map an unused page
-    let page = Page::containing_address(VirtAddr::new(0xdeadbeaf000));
-    memory::create_example_mapping(page, &mut mapper, &mut frame_allocator);
+    // map the VGA text buffer
+    let vga_buffer = unsafe {
+        MmioRegion::map(PhysAddr::new(0xb8000), 4000, CacheMode::WriteThrough)
+            .expect("failed to map the VGA buffer")
+    };
 
     // write the string `New!` to the screen through the new mapping
-    let page_ptr: *mut u64 = page.start_address().as_mut_ptr();
-    unsafe { page_ptr.offset(400).write_volatile(0x_f021_f077_f065_f04e) };
+    vga_buffer.write::<u64>(400 * 8, 0x_f021_f077_f065_f04e);
 
     #[cfg(test)]
     test_main();
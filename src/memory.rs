@@ -1,23 +1,103 @@
+use bitmap::BitmapFrameAllocator;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 use x86_64::{
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+        FrameAllocator, OffsetPageTable, PageSize, PageTable, PhysFrame, Size4KiB,
         UnusedPhysFrame,
     },
     PhysAddr, VirtAddr,
 };
 
+pub mod address_space;
+pub mod bitmap;
+pub mod buddy;
+pub mod cow;
+pub mod guard;
+pub mod lazy;
+pub mod mmio;
+pub mod physical;
+pub mod protect;
+pub mod stack;
+pub mod vmm;
+pub mod walk;
+
+/// The kernel's page table mapper, for code that changes mappings after boot.
+///
+/// When both `MAPPER` and `FRAME_ALLOCATOR` are needed, `MAPPER` must be
+/// locked first.
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+/// The kernel's physical frame allocator, for code that maps memory after boot.
+pub static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+/// The virtual address at which the complete physical memory is mapped, as
+/// passed to `init`.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
 /// Initialize a new OffsetPageTable.
 ///
 /// This function is unsafe because the caller must guarantee that the
 /// complete physical memory is mapped to virtual memory at the passed
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
+///
+/// Also enables the `NO_EXECUTE` flag and kernel write protection, so that the
+/// mappings created afterwards can use them.
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    protect::enable();
+    address_space::init();
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// Returns the virtual address at which the complete physical memory is mapped.
+///
+/// Panics if `init` was not called yet.
+pub fn physical_memory_offset() -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    assert!(offset != 0, "memory::init was not called");
+    VirtAddr::new(offset)
+}
+
+/// Makes the given mapper and frame allocator available through `MAPPER` and
+/// `FRAME_ALLOCATOR`.
+///
+/// Also creates the level 3 tables of the virtual memory manager's range, so
+/// that every `AddressSpace` sees the heap and stack mappings that are created
+/// later.
+pub fn init_globals(mapper: OffsetPageTable<'static>, mut frame_allocator: BitmapFrameAllocator) {
+    // the mapper is not used while the level 4 table changes
+    unsafe { address_space::preallocate_kernel_entries(&mut frame_allocator) };
+    *MAPPER.lock() = Some(mapper);
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+/// Returns the number of bytes of physical memory that the kernel's frame
+/// allocator manages.
+///
+/// Panics if `init_globals` was not called yet.
+pub fn total_memory() -> u64 {
+    frames_in_bytes(|allocator| allocator.usable_frames())
+}
+
+/// Returns the number of bytes of physical memory that are currently free.
+///
+/// Panics if `init_globals` was not called yet.
+pub fn free_memory() -> u64 {
+    frames_in_bytes(|allocator| allocator.free_frames())
+}
+
+fn frames_in_bytes(frames: impl FnOnce(&BitmapFrameAllocator) -> usize) -> u64 {
+    let frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator
+        .as_ref()
+        .expect("memory::init_globals was not called");
+    frames(frame_allocator) as u64 * Size4KiB::SIZE
+}
+
 /// Returns a mutable reference to the active level 4 table.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -25,32 +105,23 @@ pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static>
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    &mut *active_level_4_table_ptr(physical_memory_offset) // unsafe
+}
+
+/// Returns a raw pointer to the active level 4 table.
+///
+/// Unlike `active_level_4_table`, this can be called any number of times. This
+/// function is unsafe because the caller must guarantee that the complete
+/// physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`.
+unsafe fn active_level_4_table_ptr(physical_memory_offset: VirtAddr) -> *mut PageTable {
     use x86_64::registers::control::Cr3;
 
     let (level_4_table_frame, _) = Cr3::read();
 
     let phys = level_4_table_frame.start_address();
     let virt = physical_memory_offset + phys.as_u64();
-    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
-
-    &mut *page_table_ptr // unsafe
-}
-
-/// Creates an example mapping for the given page to frame `0xb8000`.
-pub fn create_example_mapping(
-    page: Page,
-    mapper: &mut OffsetPageTable,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) {
-    use x86_64::structures::paging::PageTableFlags as Flags;
-
-    let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
-    // FIXME: ONLY FOR TEMPORARY TESTING
-    let unused_frame = unsafe { UnusedPhysFrame::new(frame) };
-    let flags = Flags::PRESENT | Flags::WRITABLE;
-
-    let map_to_result = mapper.map_to(page, unused_frame, flags, frame_allocator);
-    map_to_result.expect("map_to failed").flush();
+    virt.as_mut_ptr()
 }
 
 /// A FrameAllocator that always returns `None`.
@@ -0,0 +1,218 @@
+use super::bitmap::BitmapFrameAllocator;
+use super::vmm::{KERNEL_SPACE_END, KERNEL_SPACE_START};
+use super::{physical_memory_offset, FRAME_ALLOCATOR};
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PageTableEntry,
+        PageTableFlags, PhysFrame, Size2MiB, Size4KiB, UnusedPhysFrame,
+    },
+    PhysAddr,
+};
+
+/// The start of the range that every address space maps on its own.
+pub const USER_SPACE_START: u64 = 0x_0000_0080_0000_0000;
+
+/// The end of the range that every address space maps on its own.
+pub const USER_SPACE_END: u64 = 0x_0000_4000_0000_0000;
+
+/// The level 4 entries that cover `USER_SPACE_START..USER_SPACE_END`.
+const USER_ENTRIES: Range<usize> = 1..128;
+
+/// The level 4 entries that cover the virtual memory manager's range, which
+/// contains the heap and all kernel stacks.
+const KERNEL_SPACE_ENTRIES: Range<usize> =
+    (KERNEL_SPACE_START >> 39) as usize..((KERNEL_SPACE_END - 1) >> 39) as usize + 1;
+
+/// The start address of the bootloader's level 4 table, set by `memory::init`.
+static KERNEL_LEVEL_4_TABLE: AtomicU64 = AtomicU64::new(0);
+
+/// A set of page tables that shares the kernel's mappings but maps
+/// `USER_SPACE_START..USER_SPACE_END` independently.
+///
+/// All level 4 entries outside of the user range point to the kernel's level 3
+/// tables, so kernel mappings are visible in every address space. The tables
+/// of the virtual memory manager's range are created up front by
+/// `memory::init_globals`, so that its later mappings are visible too. The page tables and all frames mapped in the user range
+/// are owned by the address space and are returned to `FRAME_ALLOCATOR` when it
+/// is dropped.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    mapper: OffsetPageTable<'static>,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user range.
+    ///
+    /// Returns `None` if no frame is left for the level 4 table.
+    pub fn new(frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Option<Self> {
+        let level_4_frame = *frame_allocator.allocate_frame()?;
+        let level_4_table = unsafe {
+            let table = table_ptr(level_4_frame.start_address());
+            table.write(PageTable::new());
+            &mut *table
+        };
+
+        let kernel_table = unsafe { &*table_ptr(kernel_level_4_frame().start_address()) };
+        for (index, entry) in kernel_table.iter().enumerate() {
+            if !USER_ENTRIES.contains(&index) {
+                level_4_table[index] = entry.clone();
+            }
+        }
+
+        // the table is only referenced through this mapper
+        let mapper = unsafe { OffsetPageTable::new(level_4_table, physical_memory_offset()) };
+        Some(AddressSpace {
+            level_4_frame,
+            mapper,
+        })
+    }
+
+    /// Returns a mapper for the page tables of this address space.
+    ///
+    /// The address space doesn't need to be active to change its mappings.
+    pub fn mapper(&mut self) -> &mut OffsetPageTable<'static> {
+        &mut self.mapper
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Switches to this address space.
+    ///
+    /// This function is unsafe because the caller must guarantee that no
+    /// references into the user range of the previous address space are used
+    /// afterwards.
+    pub unsafe fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "the active address space can't be dropped");
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator
+            .as_mut()
+            .expect("memory globals are not initialized");
+
+        let level_4_table = unsafe { &*table_ptr(self.level_4_frame.start_address()) };
+        for index in USER_ENTRIES {
+            // the user range is owned by this address space, which is not active
+            unsafe { free_entry(&level_4_table[index], 4, frame_allocator) };
+        }
+        frame_allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(self.level_4_frame) });
+    }
+}
+
+/// Switches back to the kernel's address space.
+///
+/// This function is unsafe for the same reasons as `AddressSpace::activate`.
+pub unsafe fn activate_kernel() {
+    let (_, flags) = Cr3::read();
+    Cr3::write(kernel_level_4_frame(), flags);
+}
+
+/// Remembers the active level 4 table as the kernel's table.
+///
+/// Panics if the bootloader mapped anything into the user range, e.g. the
+/// physical memory or the boot info, since address spaces don't share those
+/// entries. `package.metadata.bootloader` in Cargo.toml places the
+/// bootloader's mappings in the upper half.
+pub(super) fn init() {
+    let (level_4_frame, _) = Cr3::read();
+    KERNEL_LEVEL_4_TABLE.store(level_4_frame.start_address().as_u64(), Ordering::Relaxed);
+
+    let kernel_table = unsafe { &*table_ptr(level_4_frame.start_address()) };
+    for index in USER_ENTRIES {
+        assert!(
+            kernel_table[index].is_unused(),
+            "the bootloader mapped memory into the user range"
+        );
+    }
+}
+
+/// Creates an empty level 3 table for every unused entry of the kernel's level
+/// 4 table that covers the virtual memory manager's range.
+///
+/// `AddressSpace::new` copies the kernel entries, so a heap or stack mapping
+/// that added a level 4 entry later would be missing in the address spaces
+/// that exist already.
+///
+/// This function is unsafe because the caller must guarantee that the kernel's
+/// level 4 table is not used concurrently.
+pub(super) unsafe fn preallocate_kernel_entries(
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    // the entries are accessed through raw pointers, since the kernel's mapper
+    // holds a reference to the table
+    let entries = table_ptr(kernel_level_4_frame().start_address()) as *mut PageTableEntry;
+    for index in KERNEL_SPACE_ENTRIES {
+        let entry = entries.add(index);
+        if !entry.read().is_unused() {
+            continue;
+        }
+        let frame = frame_allocator
+            .allocate_frame()
+            .expect("no frames left for the kernel's level 3 tables");
+        table_ptr(frame.start_address()).write(PageTable::new());
+        let mut new_entry = PageTableEntry::new();
+        new_entry.set_frame(*frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        entry.write(new_entry);
+    }
+}
+
+fn kernel_level_4_frame() -> PhysFrame {
+    let addr = KERNEL_LEVEL_4_TABLE.load(Ordering::Relaxed);
+    assert!(addr != 0, "memory::init was not called");
+    PhysFrame::containing_address(PhysAddr::new(addr))
+}
+
+fn table_ptr(addr: PhysAddr) -> *mut PageTable {
+    (physical_memory_offset() + addr.as_u64()).as_mut_ptr()
+}
+
+/// Frees everything that the given entry of a level `level` table maps,
+/// including the page tables below it.
+///
+/// This function is unsafe because the caller must guarantee that the frames
+/// are owned by the entry and no longer in use.
+unsafe fn free_entry(
+    entry: &PageTableEntry,
+    level: usize,
+    frame_allocator: &mut BitmapFrameAllocator,
+) {
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) {
+        return;
+    }
+    let huge = flags.contains(PageTableFlags::HUGE_PAGE);
+    match level {
+        1 => {
+            let frame: PhysFrame = PhysFrame::containing_address(entry.addr());
+            frame_allocator.deallocate_frame(UnusedPhysFrame::new(frame));
+        }
+        2 if huge => {
+            let frame: PhysFrame<Size2MiB> = PhysFrame::containing_address(entry.addr());
+            frame_allocator.deallocate_frame(UnusedPhysFrame::new(frame));
+        }
+        // 1 GiB frames are never handed out by the frame allocator
+        3 if huge => {}
+        _ => {
+            let table = &*table_ptr(entry.addr());
+            for child in table.iter() {
+                free_entry(child, level - 1, frame_allocator);
+            }
+            let frame: PhysFrame = PhysFrame::containing_address(entry.addr());
+            frame_allocator.deallocate_frame(UnusedPhysFrame::new(frame));
+        }
+    }
+}
@@ -0,0 +1,303 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::{mem, slice};
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB,
+        UnusedPhysFrame,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+const BITS_PER_WORD: usize = 64;
+
+/// The number of bitmap words that cover one 2 MiB frame.
+const WORDS_PER_HUGE_FRAME: usize = (Size2MiB::SIZE / FRAME_SIZE) as usize / BITS_PER_WORD;
+
+/// A FrameAllocator that tracks every physical frame with a single bit.
+///
+/// The bitmap is built once from the bootloader's memory map and stored in the
+/// first usable region that is large enough to hold it. A set bit means that
+/// the frame is in use (or not usable at all), a cleared bit means that it is
+/// free.
+///
+/// Next to the bitmap, every frame has a reference count for frames that are
+/// shared between mappings, e.g. for copy-on-write. It counts the references
+/// in addition to the first one, so it is zero for frames with a single owner.
+///
+/// A second bitmap has one bit for every 2 MiB chunk of the first one, which
+/// is set while the chunk is allocated as a single 2 MiB frame.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    huge: &'static mut [u64],
+    shared: &'static mut [u8],
+    usable_frames: usize,
+    free_frames: usize,
+    next_word: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Create a BitmapFrameAllocator from the passed memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid and that the complete physical memory is mapped to
+    /// virtual memory at the passed `physical_memory_offset`. The main requirement
+    /// is that all frames that are marked as `USABLE` in the memory map are really
+    /// unused.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        // one bit for every frame up to the end of the highest usable region
+        let frame_count = usable_regions()
+            .map(|r| r.range.end_addr() / FRAME_SIZE)
+            .max()
+            .unwrap_or(0) as usize;
+        let word_count = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_size = (word_count * mem::size_of::<u64>()) as u64;
+        // one bit for every 2 MiB chunk of the bitmap
+        let chunk_count = word_count / WORDS_PER_HUGE_FRAME;
+        let huge_word_count = (chunk_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let huge_size = (huge_word_count * mem::size_of::<u64>()) as u64;
+        // one reference count byte for every bit of the bitmap
+        let shared_count = word_count * BITS_PER_WORD;
+        let metadata_size = bitmap_size + huge_size + shared_count as u64;
+
+        // store the bitmap and the reference counts at the start of the first
+        // usable region that fits them
+        let bitmap_start = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= metadata_size)
+            .map(|r| r.range.start_addr())
+            .expect("no usable region is large enough for the frame bitmap");
+        let bitmap_ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmap = slice::from_raw_parts_mut(bitmap_ptr, word_count);
+        let huge_ptr: *mut u64 = (physical_memory_offset + bitmap_start + bitmap_size).as_mut_ptr();
+        let huge = slice::from_raw_parts_mut(huge_ptr, huge_word_count);
+        for word in huge.iter_mut() {
+            *word = 0;
+        }
+        let shared_start = bitmap_start + bitmap_size + huge_size;
+        let shared_ptr: *mut u8 = (physical_memory_offset + shared_start).as_mut_ptr();
+        let shared = slice::from_raw_parts_mut(shared_ptr, shared_count);
+        for count in shared.iter_mut() {
+            *count = 0;
+        }
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            huge,
+            shared,
+            usable_frames: 0,
+            free_frames: 0,
+            next_word: 0,
+        };
+
+        // everything is used until the memory map says otherwise
+        for word in allocator.bitmap.iter_mut() {
+            *word = u64::max_value();
+        }
+        for region in usable_regions() {
+            let start = region.range.start_addr() / FRAME_SIZE;
+            let end = region.range.end_addr() / FRAME_SIZE;
+            for index in start..end {
+                allocator.set_free(index as usize);
+            }
+        }
+
+        // the frames holding the bitmap itself are not available
+        let bitmap_frames = (metadata_size + FRAME_SIZE - 1) / FRAME_SIZE;
+        let bitmap_start_frame = bitmap_start / FRAME_SIZE;
+        for index in bitmap_start_frame..bitmap_start_frame + bitmap_frames {
+            allocator.set_used(index as usize);
+        }
+
+        allocator.usable_frames = allocator.free_frames;
+        allocator
+    }
+
+    /// Returns the number of frames that can be handed out by this allocator.
+    pub fn usable_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    /// Returns the number of frames that are currently free.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Returns the number of frames that are currently allocated.
+    pub fn used_frames(&self) -> usize {
+        self.usable_frames - self.free_frames
+    }
+
+    /// Adds a reference to an allocated frame, e.g. because it is mapped
+    /// copy-on-write a second time.
+    ///
+    /// Every reference is dropped through `deallocate_frame` and the frame is
+    /// only freed with the last one.
+    pub fn share_frame(&mut self, frame: PhysFrame) {
+        let index = self.frame_index(frame);
+        assert!(self.is_used(index), "frame {:?} is not allocated", frame);
+        let count = &mut self.shared[index];
+        *count = count.checked_add(1).expect("too many references to a frame");
+    }
+
+    /// Returns the number of references to the given frame, which is zero for
+    /// free frames.
+    pub fn ref_count(&self, frame: PhysFrame) -> usize {
+        let index = self.frame_index(frame);
+        if self.is_used(index) {
+            self.shared[index] as usize + 1
+        } else {
+            0
+        }
+    }
+
+    /// Returns the bitmap index of the given frame.
+    ///
+    /// Panics if the frame is not managed by this allocator.
+    fn frame_index(&self, frame: PhysFrame) -> usize {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(
+            index < self.bitmap.len() * BITS_PER_WORD,
+            "frame {:?} is not managed by this allocator",
+            frame
+        );
+        index
+    }
+
+    /// Returns whether the given 2 MiB chunk is allocated as a single frame.
+    fn is_huge(&self, chunk: usize) -> bool {
+        self.huge
+            .get(chunk / BITS_PER_WORD)
+            .map_or(false, |word| word & (1 << (chunk % BITS_PER_WORD)) != 0)
+    }
+
+    fn set_huge(&mut self, chunk: usize, huge: bool) {
+        if huge {
+            self.huge[chunk / BITS_PER_WORD] |= 1 << (chunk % BITS_PER_WORD);
+        } else {
+            self.huge[chunk / BITS_PER_WORD] &= !(1 << (chunk % BITS_PER_WORD));
+        }
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set_used(&mut self, index: usize) {
+        debug_assert!(!self.is_used(index));
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+        self.free_frames -= 1;
+    }
+
+    fn set_free(&mut self, index: usize) {
+        debug_assert!(self.is_used(index));
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+        self.free_frames += 1;
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame> {
+        if self.free_frames == 0 {
+            return None;
+        }
+
+        // start searching at the word of the last allocation, since all words
+        // before it are likely to be full already
+        let word_count = self.bitmap.len();
+        for offset in 0..word_count {
+            let word_index = (self.next_word + offset) % word_count;
+            let word = self.bitmap[word_index];
+            if word != u64::max_value() {
+                let index = word_index * BITS_PER_WORD + (!word).trailing_zeros() as usize;
+                self.set_used(index);
+                self.next_word = word_index;
+
+                let addr = PhysAddr::new(index as u64 * FRAME_SIZE);
+                let frame = PhysFrame::containing_address(addr);
+                // the bitmap guarantees that nobody else uses the frame
+                return Some(unsafe { UnusedPhysFrame::new(frame) });
+            }
+        }
+
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame) {
+        let index = self.frame_index(*frame);
+        assert!(self.is_used(index), "frame {:?} is already free", *frame);
+        let chunk = index / BITS_PER_WORD / WORDS_PER_HUGE_FRAME;
+        assert!(!self.is_huge(chunk), "frame {:?} is part of a 2 MiB frame", *frame);
+
+        if self.shared[index] > 0 {
+            // other mappings still reference the frame
+            self.shared[index] -= 1;
+            return;
+        }
+        self.set_free(index);
+        if index / BITS_PER_WORD < self.next_word {
+            self.next_word = index / BITS_PER_WORD;
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size2MiB>> {
+        // a 2 MiB frame is free if all words that cover it are zero
+        let chunk = self
+            .bitmap
+            .chunks_exact(WORDS_PER_HUGE_FRAME)
+            .position(|words| words.iter().all(|&word| word == 0))?;
+        let first_word = chunk * WORDS_PER_HUGE_FRAME;
+        for word in self.bitmap[first_word..first_word + WORDS_PER_HUGE_FRAME].iter_mut() {
+            *word = u64::max_value();
+        }
+        self.set_huge(chunk, true);
+        self.free_frames -= WORDS_PER_HUGE_FRAME * BITS_PER_WORD;
+
+        let addr = PhysAddr::new((first_word * BITS_PER_WORD) as u64 * FRAME_SIZE);
+        let frame = PhysFrame::containing_address(addr);
+        // the bitmap guarantees that nobody else uses the frame
+        Some(unsafe { UnusedPhysFrame::new(frame) })
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame<Size2MiB>) {
+        let first_word = (frame.start_address().as_u64() / FRAME_SIZE) as usize / BITS_PER_WORD;
+        assert!(
+            first_word + WORDS_PER_HUGE_FRAME <= self.bitmap.len(),
+            "frame {:?} is not managed by this allocator",
+            *frame
+        );
+        let chunk = first_word / WORDS_PER_HUGE_FRAME;
+        assert!(
+            self.is_huge(chunk),
+            "frame {:?} was not allocated as a 2 MiB frame",
+            *frame
+        );
+        let first_index = first_word * BITS_PER_WORD;
+        let indexes = first_index..first_index + WORDS_PER_HUGE_FRAME * BITS_PER_WORD;
+        assert!(
+            self.shared[indexes].iter().all(|&count| count == 0),
+            "frame {:?} is still shared",
+            *frame
+        );
+
+        for word in self.bitmap[first_word..first_word + WORDS_PER_HUGE_FRAME].iter_mut() {
+            *word = 0;
+        }
+        self.set_huge(chunk, false);
+        self.free_frames += WORDS_PER_HUGE_FRAME * BITS_PER_WORD;
+        if first_word < self.next_word {
+            self.next_word = first_word;
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::{mem, slice};
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, PhysFrameRange, Size4KiB,
+        UnusedPhysFrame,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+
+/// The largest block order, i.e. blocks are at most `2^MAX_ORDER` frames (4 MiB).
+pub const MAX_ORDER: usize = 10;
+
+/// The header of a free block, stored in its first frame. The links are the
+/// physical addresses of the neighbouring blocks in the free list.
+struct FreeBlock {
+    next: Option<u64>,
+    prev: Option<u64>,
+}
+
+/// A physical frame allocator that hands out power-of-two runs of contiguous
+/// frames.
+///
+/// A block of order `n` consists of `2^n` frames and always starts at a physical
+/// address that is aligned to its size. Freed blocks are merged with their buddy
+/// whenever the buddy is free too. The doubly linked free lists are stored in
+/// the free frames themselves, which are accessed through the physical memory
+/// mapping. A bitmap per order records which blocks are free, so that the buddy
+/// of a block is found and unlinked in constant time.
+pub struct BuddyFrameAllocator {
+    free_lists: [Option<u64>; MAX_ORDER + 1],
+    /// The free bitmaps of all orders, one after another.
+    free_map: &'static mut [u64],
+    /// The index of the first word of each order's bitmap in `free_map`.
+    map_offsets: [usize; MAX_ORDER + 1],
+    /// The physical address of the first frame that the bitmaps cover.
+    start: u64,
+    /// The number of frames that the bitmaps cover.
+    frames: usize,
+    physical_memory_offset: VirtAddr,
+    free_frames: usize,
+}
+
+impl BuddyFrameAllocator {
+    /// Creates an empty BuddyFrameAllocator for the `frames` frames starting
+    /// at `start`, which must be aligned to the largest block size.
+    ///
+    /// `free_map` stores the free bitmaps and must hold at least
+    /// `free_map_words(frames)` words.
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// complete physical memory is mapped to virtual memory at the passed
+    /// `physical_memory_offset`.
+    pub unsafe fn new(
+        physical_memory_offset: VirtAddr,
+        start: PhysAddr,
+        frames: usize,
+        free_map: &'static mut [u64],
+    ) -> Self {
+        assert!(
+            start.is_aligned(block_size(MAX_ORDER)),
+            "start must be aligned to the largest block size"
+        );
+        assert!(free_map.len() >= free_map_words(frames), "free map is too small");
+        for word in free_map.iter_mut() {
+            *word = 0;
+        }
+
+        let mut map_offsets = [0; MAX_ORDER + 1];
+        for order in 1..=MAX_ORDER {
+            map_offsets[order] = map_offsets[order - 1] + words_for_order(frames, order - 1);
+        }
+
+        BuddyFrameAllocator {
+            free_lists: [None; MAX_ORDER + 1],
+            free_map,
+            map_offsets,
+            start: start.as_u64(),
+            frames,
+            physical_memory_offset,
+            free_frames: 0,
+        }
+    }
+
+    /// Create a BuddyFrameAllocator from the usable regions of the passed memory map.
+    ///
+    /// The free bitmaps are stored at the start of the first usable region that
+    /// is large enough to hold them.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid and that the complete physical memory is mapped at
+    /// `physical_memory_offset`. The main requirement is that all frames that are
+    /// marked as `USABLE` in it are really unused, so the allocator must not be
+    /// used together with another allocator that is seeded from the same map.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        // cover every frame up to the end of the highest usable region
+        let frames = usable_regions()
+            .map(|r| r.range.end_addr() / FRAME_SIZE)
+            .max()
+            .unwrap_or(0) as usize;
+        let words = free_map_words(frames);
+        let map_size = (words * mem::size_of::<u64>()) as u64;
+        let map_start = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= map_size)
+            .map(|r| r.range.start_addr())
+            .expect("no usable region is large enough for the free bitmaps");
+        let map_ptr: *mut u64 = (physical_memory_offset + map_start).as_mut_ptr();
+        let free_map = slice::from_raw_parts_mut(map_ptr, words);
+
+        let mut allocator = Self::new(physical_memory_offset, PhysAddr::new(0), frames, free_map);
+        for region in usable_regions() {
+            let mut start = region.range.start_addr();
+            if start == map_start {
+                // the frames holding the bitmaps are not available
+                start += map_size;
+            }
+            allocator.add_region(PhysAddr::new(start), PhysAddr::new(region.range.end_addr()));
+        }
+        allocator
+    }
+
+    /// Adds the given physical memory range to the allocator.
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// given range is unused and not managed by any other allocator.
+    pub unsafe fn add_region(&mut self, start: PhysAddr, end: PhysAddr) {
+        let mut start = start.align_up(FRAME_SIZE).as_u64();
+        let end = end.align_down(FRAME_SIZE).as_u64();
+        assert!(
+            start >= end || (start >= self.start && self.contains(end - FRAME_SIZE, 0)),
+            "region is not covered by the free bitmaps"
+        );
+
+        // split the range into the largest naturally aligned blocks
+        while start < end {
+            let mut order = ((start / FRAME_SIZE).trailing_zeros() as usize).min(MAX_ORDER);
+            while start + block_size(order) > end {
+                order -= 1;
+            }
+            self.free_block(start, order);
+            self.free_frames += 1 << order;
+            start += block_size(order);
+        }
+    }
+
+    /// Returns the number of frames that are currently free.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Allocates a block of `2^order` contiguous frames.
+    ///
+    /// The returned range is aligned to its size.
+    pub fn allocate(&mut self, order: usize) -> Option<PhysFrameRange> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        // find the smallest free block that is large enough
+        let mut current = (order..=MAX_ORDER).find(|&o| self.free_lists[o].is_some())?;
+        let addr = self.pop(current).expect("free list is not empty");
+
+        // split it until it has the requested size, freeing the upper halves
+        while current > order {
+            current -= 1;
+            // the upper half is part of the free block we just removed
+            unsafe { self.push(addr + block_size(current), current) };
+        }
+
+        self.free_frames -= 1 << order;
+        let start = PhysFrame::containing_address(PhysAddr::new(addr));
+        Some(PhysFrame::range(start, start + (1 << order)))
+    }
+
+    /// Allocates a block of `2^order` contiguous frames whose start address is
+    /// aligned to `align` bytes.
+    ///
+    /// `align` must be a power of two.
+    pub fn allocate_aligned(&mut self, order: usize, align: u64) -> Option<PhysFrameRange> {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        // blocks are naturally aligned, so a larger alignment requires a larger block
+        let align_order = order_for_frames((align / FRAME_SIZE) as usize);
+        let block_order = order.max(align_order);
+        let block = self.allocate(block_order)?;
+
+        // give back the part of the block that is not needed
+        let start = block.start.start_address().as_u64();
+        let mut current = block_order;
+        while current > order {
+            current -= 1;
+            unsafe { self.free_block(start + block_size(current), current) };
+            self.free_frames += 1 << current;
+        }
+        Some(PhysFrame::range(block.start, block.start + (1 << order)))
+    }
+
+    /// Returns a block that was allocated through `allocate` or `allocate_aligned`.
+    ///
+    /// This function is unsafe because the caller must guarantee that the range
+    /// was returned by this allocator and that it is no longer in use.
+    pub unsafe fn deallocate(&mut self, range: PhysFrameRange) {
+        let start = range.start.start_address().as_u64();
+        let frames = (range.end.start_address().as_u64() - start) / FRAME_SIZE;
+        assert!(frames.is_power_of_two(), "range is not a buddy block");
+        let order = frames.trailing_zeros() as usize;
+        assert!(start % block_size(order) == 0, "range is not a buddy block");
+        assert!(self.contains(start, order), "range is not managed by this allocator");
+        // freeing a block twice, or a part of a free block, would add a second
+        // node for the same memory to the free lists
+        for enclosing_order in order..=MAX_ORDER {
+            let enclosing = start & !(block_size(enclosing_order) - 1);
+            let already_free =
+                self.contains(enclosing, enclosing_order) && self.is_free(enclosing, enclosing_order);
+            assert!(!already_free, "block is already free");
+        }
+
+        self.free_block(start, order);
+        self.free_frames += 1 << order;
+    }
+
+    /// Adds the block at `addr` to the free lists, merging it with its buddy as
+    /// long as possible.
+    unsafe fn free_block(&mut self, mut addr: u64, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = addr ^ block_size(order);
+            if !self.contains(buddy, order) || !self.is_free(buddy, order) {
+                break;
+            }
+            self.unlink(buddy, order);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(addr, order);
+    }
+
+    /// Pushes the block with the given physical address to the front of a free list.
+    unsafe fn push(&mut self, addr: u64, order: usize) {
+        let next = self.free_lists[order];
+        self.block(addr).write(FreeBlock { next, prev: None });
+        if let Some(next) = next {
+            (*self.block(next)).prev = Some(addr);
+        }
+        self.free_lists[order] = Some(addr);
+        self.set_free(addr, order, true);
+    }
+
+    /// Removes the first block of a free list and returns its physical address.
+    fn pop(&mut self, order: usize) -> Option<u64> {
+        let addr = self.free_lists[order]?;
+        // the block is in the free list
+        unsafe { self.unlink(addr, order) };
+        Some(addr)
+    }
+
+    /// Removes the block with the given physical address from its free list.
+    ///
+    /// This function is unsafe because the block must be in the free list of
+    /// the given order.
+    unsafe fn unlink(&mut self, addr: u64, order: usize) {
+        let FreeBlock { next, prev } = self.block(addr).read();
+        match prev {
+            Some(prev) => (*self.block(prev)).next = next,
+            None => self.free_lists[order] = next,
+        }
+        if let Some(next) = next {
+            (*self.block(next)).prev = prev;
+        }
+        self.set_free(addr, order, false);
+    }
+
+    /// Returns a pointer to the header of the free block at the given address.
+    fn block(&self, addr: u64) -> *mut FreeBlock {
+        (self.physical_memory_offset + addr).as_mut_ptr()
+    }
+
+    /// Returns whether the block at `addr` lies within the free bitmaps.
+    fn contains(&self, addr: u64, order: usize) -> bool {
+        addr >= self.start && (addr - self.start) / FRAME_SIZE + (1 << order) <= self.frames as u64
+    }
+
+    /// Returns the word and the bit of the given block in the free bitmaps.
+    fn map_position(&self, addr: u64, order: usize) -> (usize, u64) {
+        let index = (((addr - self.start) / FRAME_SIZE) >> order) as usize;
+        (self.map_offsets[order] + index / 64, 1 << (index % 64))
+    }
+
+    fn is_free(&self, addr: u64, order: usize) -> bool {
+        let (word, bit) = self.map_position(addr, order);
+        self.free_map[word] & bit != 0
+    }
+
+    fn set_free(&mut self, addr: u64, order: usize, free: bool) {
+        let (word, bit) = self.map_position(addr, order);
+        if free {
+            self.free_map[word] |= bit;
+        } else {
+            self.free_map[word] &= !bit;
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame> {
+        let range = self.allocate(0)?;
+        // the frame was taken from the free lists
+        Some(unsafe { UnusedPhysFrame::new(range.start) })
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame) {
+        // the caller passes ownership of the unused frame
+        unsafe { self.deallocate(PhysFrame::range(*frame, *frame + 1)) }
+    }
+}
+
+/// Returns the size of a block of the given order in bytes.
+fn block_size(order: usize) -> u64 {
+    FRAME_SIZE << order
+}
+
+/// Returns the number of words that the free bitmaps of `frames` frames need.
+pub fn free_map_words(frames: usize) -> usize {
+    (0..=MAX_ORDER).map(|order| words_for_order(frames, order)).sum()
+}
+
+/// Returns the number of words of the free bitmap of the given order.
+fn words_for_order(frames: usize, order: usize) -> usize {
+    let blocks = (frames + (1 << order) - 1) >> order;
+    (blocks + 63) / 64
+}
+
+/// Returns the smallest order whose blocks hold at least `frames` frames.
+pub fn order_for_frames(frames: usize) -> usize {
+    frames.max(1).next_power_of_two().trailing_zeros() as usize
+}
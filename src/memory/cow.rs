@@ -0,0 +1,172 @@
+use super::bitmap::BitmapFrameAllocator;
+use super::walk::{self, MappingSize};
+use super::{physical_memory_offset, FRAME_ALLOCATOR, MAPPER};
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::{FlagUpdateError, MapToError},
+            FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize,
+            PageTableFlags, PhysFrame, Size4KiB, UnusedPhysFrame,
+        },
+    },
+    VirtAddr,
+};
+
+/// The page table flag that marks a read-only page as copy-on-write.
+///
+/// Bit 9 is ignored by the CPU and free for the OS to use.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+#[derive(Debug)]
+pub enum CowError {
+    /// The page is not mapped.
+    NotMapped,
+    /// The page is part of a huge page, which can't be shared.
+    HugePage,
+    FlagUpdateFailed(FlagUpdateError),
+    MapFailed(MapToError<Size4KiB>),
+}
+
+impl From<FlagUpdateError> for CowError {
+    fn from(err: FlagUpdateError) -> Self {
+        CowError::FlagUpdateFailed(err)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for CowError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        CowError::MapFailed(err)
+    }
+}
+
+/// Prepares a mapped page for sharing and adds a reference to its frame.
+///
+/// Writable pages are made read-only and marked `COPY_ON_WRITE`, so that the
+/// next write to them copies the frame. Returns the frame and the flags with
+/// which it has to be mapped through `map_shared`.
+///
+/// `mapper` must be the active page table.
+pub fn share(
+    page: Page,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BitmapFrameAllocator,
+) -> Result<(PhysFrame, PageTableFlags), CowError> {
+    let translation = walk::translate(page.start_address()).ok_or(CowError::NotMapped)?;
+    if translation.page_size != MappingSize::Size4KiB {
+        return Err(CowError::HugePage);
+    }
+    let frame = PhysFrame::containing_address(translation.phys_addr);
+
+    let mut flags = translation.flags - PageTableFlags::ACCESSED - PageTableFlags::DIRTY;
+    if flags.contains(PageTableFlags::WRITABLE) {
+        flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+        mapper.update_flags(page, flags)?.flush();
+    }
+    frame_allocator.share_frame(frame);
+    Ok((frame, flags))
+}
+
+/// Maps a frame that was returned by `share` to the given page.
+///
+/// The reference that `share` added is dropped if the mapping fails.
+///
+/// This function is unsafe because the caller must guarantee that the frame
+/// and flags were returned by `share` and that each `share` is followed by
+/// only one `map_shared`.
+pub unsafe fn map_shared(
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BitmapFrameAllocator,
+) -> Result<(), CowError> {
+    match mapper.map_to(page, UnusedPhysFrame::new(frame), flags, frame_allocator) {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(err) => {
+            frame_allocator.deallocate_frame(UnusedPhysFrame::new(frame));
+            Err(err.into())
+        }
+    }
+}
+
+/// Gives the faulting page its own writable frame if the fault is a write to
+/// a copy-on-write page.
+///
+/// The frame is copied unless the page holds the last reference to it.
+/// Returns whether the fault was handled, in which case the faulting
+/// instruction can be resumed. Faults that happen while the page tables or the
+/// frame allocator are locked by the interrupted code can't be handled.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let write_violation =
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if !error_code.contains(write_violation) {
+        return false;
+    }
+    let translation = match walk::translate(addr) {
+        Some(t) if t.page_size == MappingSize::Size4KiB && t.flags.contains(COPY_ON_WRITE) => t,
+        _ => return false,
+    };
+
+    let mut mapper = match MAPPER.try_lock() {
+        Some(mapper) => mapper,
+        None => return false,
+    };
+    let mut frame_allocator = match FRAME_ALLOCATOR.try_lock() {
+        Some(frame_allocator) => frame_allocator,
+        None => return false,
+    };
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return false,
+    };
+
+    let page: Page = Page::containing_address(addr);
+    let frame = PhysFrame::containing_address(translation.phys_addr);
+    let volatile = PageTableFlags::ACCESSED | PageTableFlags::DIRTY;
+    let flags = (translation.flags - COPY_ON_WRITE - volatile) | PageTableFlags::WRITABLE;
+
+    if frame_allocator.ref_count(frame) == 1 {
+        // all other mappings are gone, so the frame can be written in place
+        return match mapper.update_flags(page, flags) {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => false,
+        };
+    }
+
+    let new_frame: UnusedPhysFrame = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    // both frames are accessible through the physical memory mapping
+    let offset = physical_memory_offset();
+    let src: *const u8 = (offset + frame.start_address().as_u64()).as_ptr();
+    let dst: *mut u8 = (offset + new_frame.start_address().as_u64()).as_mut_ptr();
+    unsafe { dst.copy_from_nonoverlapping(src, Size4KiB::SIZE as usize) };
+
+    match mapper.unmap(page) {
+        Ok((old_frame, flush)) => {
+            flush.flush();
+            // drops this page's reference, the other mappings keep the frame
+            frame_allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(old_frame) });
+        }
+        Err(_) => {
+            frame_allocator.deallocate_frame(new_frame);
+            return false;
+        }
+    }
+    match mapper.map_to(page, new_frame, flags, frame_allocator) {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        // the page tables of the page still exist, so this doesn't happen
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,77 @@
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+/// The maximum number of guard regions.
+const MAX_GUARD_REGIONS: usize = 32;
+
+/// Where a guard region lies relative to the memory it protects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardPosition {
+    /// The guard is below the protected memory, e.g. at the end of a stack.
+    Below,
+    /// The guard is above the protected memory.
+    Above,
+}
+
+/// An unmapped virtual range that protects the memory next to it.
+#[derive(Debug, Clone, Copy)]
+pub struct GuardRegion {
+    /// The name of the protected stack or heap.
+    pub owner: &'static str,
+    pub position: GuardPosition,
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+}
+
+impl GuardRegion {
+    /// Describes what a hit of the guard region means.
+    pub fn violation(&self) -> &'static str {
+        match self.position {
+            GuardPosition::Below => "overflowed below its start",
+            GuardPosition::Above => "overflowed above its end",
+        }
+    }
+}
+
+static GUARD_REGIONS: Mutex<[Option<GuardRegion>; MAX_GUARD_REGIONS]> =
+    Mutex::new([None; MAX_GUARD_REGIONS]);
+
+/// Registers an unmapped guard region so that hits can be reported.
+///
+/// The caller must make sure that the range stays unmapped.
+pub fn register(owner: &'static str, position: GuardPosition, start: VirtAddr, end: VirtAddr) {
+    let mut regions = GUARD_REGIONS.lock();
+    let slot = regions
+        .iter_mut()
+        .find(|r| r.is_none())
+        .expect("too many guard regions");
+    *slot = Some(GuardRegion {
+        owner,
+        position,
+        start,
+        end,
+    });
+}
+
+/// Removes the guard region that starts at the given address.
+pub fn unregister(start: VirtAddr) {
+    let mut regions = GUARD_REGIONS.lock();
+    for slot in regions.iter_mut() {
+        if slot.map_or(false, |r| r.start == start) {
+            *slot = None;
+        }
+    }
+}
+
+/// Returns the guard region that contains the given address.
+///
+/// This is called from fault handlers, so it returns `None` instead of
+/// blocking if the registry is locked by the interrupted code.
+pub fn find(addr: VirtAddr) -> Option<GuardRegion> {
+    let regions = GUARD_REGIONS.try_lock()?;
+    regions
+        .iter()
+        .flatten()
+        .find(|r| r.start <= addr && addr < r.end)
+        .copied()
+}
@@ -0,0 +1,140 @@
+use super::vmm::{self, VirtualRegion, VmmError};
+use super::{physical_memory_offset, FRAME_ALLOCATOR, MAPPER};
+use spin::Mutex;
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB,
+            UnusedPhysFrame,
+        },
+    },
+    VirtAddr,
+};
+
+/// The maximum number of lazily backed regions.
+const MAX_LAZY_REGIONS: usize = 32;
+
+/// A virtual region whose pages are mapped on first access.
+#[derive(Debug, Clone, Copy)]
+struct LazyRegion {
+    region: VirtualRegion,
+    flags: PageTableFlags,
+}
+
+static LAZY_REGIONS: Mutex<[Option<LazyRegion>; MAX_LAZY_REGIONS]> =
+    Mutex::new([None; MAX_LAZY_REGIONS]);
+
+/// Reserves a virtual region of `size` bytes without mapping it.
+///
+/// The pages of the region are backed by zeroed frames with the given flags
+/// when they are first accessed. The pages are never executable.
+pub fn reserve_lazy(
+    name: &'static str,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<VirtualRegion, VmmError> {
+    let mut vmm = vmm::VMM.lock();
+    let mut regions = LAZY_REGIONS.lock();
+    let slot = regions
+        .iter_mut()
+        .find(|r| r.is_none())
+        .ok_or(VmmError::TooManyRegions)?;
+    let region = vmm.allocate(name, size, Size4KiB::SIZE)?;
+    *slot = Some(LazyRegion {
+        region,
+        flags: flags | PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+    });
+    Ok(region)
+}
+
+/// Unmaps a region that was reserved through `reserve_lazy`, frees the frames
+/// of all pages that were touched and releases the virtual range.
+///
+/// This function is unsafe because the caller must guarantee that the memory
+/// of the region is no longer used.
+pub unsafe fn free_lazy(start: VirtAddr) -> Result<(), VmmError> {
+    let mut vmm = vmm::VMM.lock();
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return Err(VmmError::Uninitialized),
+    };
+
+    {
+        let mut regions = LAZY_REGIONS.lock();
+        let slot = regions
+            .iter_mut()
+            .find(|r| r.map_or(false, |r| r.region.start == start))
+            .ok_or(VmmError::NotFound)?;
+        *slot = None;
+    }
+    let region = vmm.free(start)?;
+    vmm::unmap_pages(region.start, region.size, mapper, frame_allocator);
+    Ok(())
+}
+
+/// Maps a zeroed frame for a fault on a not yet touched page of a lazily
+/// backed region.
+///
+/// Returns whether the fault was handled, in which case the faulting
+/// instruction can be resumed. Faults that happen while the page tables or the
+/// frame allocator are locked by the interrupted code can't be handled.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        // the page is present, so this is not a first touch
+        return false;
+    }
+
+    let flags = {
+        let regions = match LAZY_REGIONS.try_lock() {
+            Some(regions) => regions,
+            None => return false,
+        };
+        match regions
+            .iter()
+            .flatten()
+            .find(|r| r.region.contains(addr))
+        {
+            Some(lazy) => lazy.flags,
+            None => return false,
+        }
+    };
+
+    let mut mapper = match MAPPER.try_lock() {
+        Some(mapper) => mapper,
+        None => return false,
+    };
+    let mut frame_allocator = match FRAME_ALLOCATOR.try_lock() {
+        Some(frame_allocator) => frame_allocator,
+        None => return false,
+    };
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return false,
+    };
+
+    let frame: UnusedPhysFrame = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    // the frame is unused, so it can be zeroed through the physical memory mapping
+    let frame_ptr: *mut u8 = (physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr();
+    unsafe { frame_ptr.write_bytes(0, Size4KiB::SIZE as usize) };
+
+    let page: Page = Page::containing_address(addr);
+    let phys = *frame;
+    match mapper.map_to(page, frame, flags, frame_allocator) {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            // this only fails if no frames are left for page tables, in which
+            // case the frame was not mapped and is still unused
+            frame_allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(phys) });
+            false
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use super::vmm::{self, VirtualRegion, VmmError};
+use super::{FRAME_ALLOCATOR, MAPPER};
+use core::mem;
+use x86_64::{
+    structures::paging::{
+        Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB, UnusedPhysFrame,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// How the CPU caches accesses to a memory mapped device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Every read and write goes to the device. Use this for registers.
+    Uncached,
+    /// Reads may be cached, writes go to the device directly. Use this for
+    /// framebuffers.
+    WriteThrough,
+}
+
+impl CacheMode {
+    fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// A physical device memory range that is mapped into kernel virtual memory.
+///
+/// The range is unmapped again when the handle is dropped. The device frames
+/// are never handed to a frame allocator.
+#[derive(Debug)]
+pub struct MmioRegion {
+    region: VirtualRegion,
+    base: VirtAddr,
+    phys_addr: PhysAddr,
+    size: u64,
+}
+
+impl MmioRegion {
+    /// Maps the physical range `phys_addr..phys_addr + size` into a free kernel
+    /// virtual range.
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// physical range belongs to a device and not to RAM that is managed by the
+    /// frame allocator.
+    pub unsafe fn map(phys_addr: PhysAddr, size: u64, mode: CacheMode) -> Result<Self, VmmError> {
+        let first_frame: PhysFrame = PhysFrame::containing_address(phys_addr);
+        let offset = phys_addr - first_frame.start_address();
+        let mapped_size = offset + size;
+
+        let mut vmm = vmm::VMM.lock();
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+            (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+            _ => return Err(VmmError::Uninitialized),
+        };
+        let region = vmm.allocate("mmio", mapped_size, Size4KiB::SIZE)?;
+
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_EXECUTE
+            | mode.flags();
+        let pages = Page::range(
+            Page::containing_address(region.start),
+            Page::containing_address(region.end()),
+        );
+        for (i, page) in pages.enumerate() {
+            // the device frame is never handed out by the frame allocator, so
+            // the mapping is the only user of it
+            let frame = UnusedPhysFrame::new(first_frame + i as u64);
+            match mapper.map_to(page, frame, flags, frame_allocator) {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    unmap_device_pages(region, mapper);
+                    vmm.free(region.start)?;
+                    return Err(err.into());
+                }
+            }
+        }
+
+        Ok(MmioRegion {
+            region,
+            base: region.start + offset,
+            phys_addr,
+            size,
+        })
+    }
+
+    /// Returns the virtual address at which the physical range starts.
+    pub fn base(&self) -> VirtAddr {
+        self.base
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys_addr
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns a pointer to a `T` at the given byte offset.
+    ///
+    /// Panics if the value doesn't fit into the region or is not aligned.
+    pub fn as_ptr<T>(&self, offset: u64) -> *mut T {
+        assert!(
+            offset + mem::size_of::<T>() as u64 <= self.size,
+            "offset out of bounds"
+        );
+        let addr = self.base + offset;
+        assert!(addr.is_aligned(mem::align_of::<T>() as u64), "unaligned access");
+        addr.as_mut_ptr()
+    }
+
+    /// Reads a `T` at the given byte offset with a volatile read.
+    pub fn read<T: Copy>(&self, offset: u64) -> T {
+        unsafe { self.as_ptr::<T>(offset).read_volatile() }
+    }
+
+    /// Writes a `T` at the given byte offset with a volatile write.
+    pub fn write<T: Copy>(&self, offset: u64, value: T) {
+        unsafe { self.as_ptr::<T>(offset).write_volatile(value) }
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        let mut vmm = vmm::VMM.lock();
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().expect("memory globals are not initialized");
+        unmap_device_pages(self.region, mapper);
+        vmm.free(self.region.start).expect("mmio region was not allocated");
+    }
+}
+
+/// Removes the mappings of the given region without freeing the frames.
+fn unmap_device_pages(region: VirtualRegion, mapper: &mut impl Mapper<Size4KiB>) {
+    let start_page: Page = Page::containing_address(region.start);
+    let end_page = Page::containing_address(region.end() - 1u64);
+    for page in Page::range_inclusive(start_page, end_page) {
+        // the frame belongs to the device, so it is not deallocated
+        if let Ok((_frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use crate::serial_println;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+
+/// Totals of the physical memory described by the bootloader's memory map.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemorySummary {
+    /// Bytes that are free for the kernel to use.
+    pub usable: u64,
+    /// Bytes occupied by the kernel image and the boot stack.
+    pub kernel: u64,
+    /// Bytes of all other regions, e.g. firmware, ACPI tables, page tables
+    /// and the bootloader itself.
+    pub reserved: u64,
+}
+
+impl MemorySummary {
+    /// Sums up the regions of the given memory map.
+    pub fn from_map(memory_map: &MemoryMap) -> Self {
+        let mut summary = MemorySummary::default();
+        for region in memory_map.iter() {
+            let size = region.range.end_addr() - region.range.start_addr();
+            match region.region_type {
+                MemoryRegionType::Usable => summary.usable += size,
+                MemoryRegionType::Kernel | MemoryRegionType::KernelStack => summary.kernel += size,
+                _ => summary.reserved += size,
+            }
+        }
+        summary
+    }
+
+    /// Returns the number of bytes that the memory map describes.
+    pub fn total(&self) -> u64 {
+        self.usable + self.kernel + self.reserved
+    }
+
+    /// Prints the totals over the serial interface.
+    pub fn dump(&self) {
+        serial_println!("usable:   {:>10} KiB", self.usable / 1024);
+        serial_println!("kernel:   {:>10} KiB", self.kernel / 1024);
+        serial_println!("reserved: {:>10} KiB", self.reserved / 1024);
+        serial_println!("total:    {:>10} KiB", self.total() / 1024);
+    }
+}
+
+/// Prints every region of the given memory map and the totals over the serial
+/// interface.
+pub fn report(memory_map: &MemoryMap) {
+    serial_println!("physical range                     size       type");
+    for region in memory_map.iter().filter(|r| r.range.end_addr() > r.range.start_addr()) {
+        let start = region.range.start_addr();
+        let end = region.range.end_addr();
+        serial_println!(
+            "{:#014x}-{:#014x} {:>10} KiB {:?}",
+            start,
+            end - 1,
+            (end - start) / 1024,
+            region.region_type,
+        );
+    }
+    MemorySummary::from_map(memory_map).dump();
+}
@@ -0,0 +1,136 @@
+use super::walk::{self, MappedRange, MappingSize};
+use super::physical_memory_offset;
+use alloc::vec::Vec;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{
+        mapper::FlagUpdateError, Mapper, OffsetPageTable, Page, PageTableFlags, Size1GiB,
+        Size2MiB, Size4KiB,
+    },
+    VirtAddr,
+};
+
+/// Enables the `NO_EXECUTE` page table flag and makes read-only pages
+/// read-only for the kernel too.
+///
+/// This function is unsafe because changing the control registers affects
+/// all existing mappings.
+pub unsafe fn enable() {
+    Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+}
+
+/// Returns whether `enable` was called.
+pub fn is_enabled() -> bool {
+    Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE)
+        && Cr0::read().contains(Cr0Flags::WRITE_PROTECT)
+}
+
+/// Applies W^X to the kernel image and makes the physical memory mapping
+/// non-executable.
+///
+/// The mapped range that contains the code of this function is the kernel's
+/// `.text`; it is made read-only and executable. All other ranges that map
+/// frames of the kernel image or the boot stack, as well as the complete
+/// physical memory mapping, are made non-executable.
+///
+/// Needs the heap, since the ranges are collected before they are changed.
+///
+/// This function is unsafe because the caller must guarantee that `mapper`
+/// is the active page table and that nothing else changes it concurrently.
+pub unsafe fn protect_kernel(
+    memory_map: &MemoryMap,
+    mapper: &mut OffsetPageTable,
+) -> Result<(), FlagUpdateError> {
+    let text = VirtAddr::new(protect_kernel as usize as u64);
+    let data = VirtAddr::from_ptr(&super::PHYSICAL_MEMORY_OFFSET);
+    let physical_memory_start = physical_memory_offset();
+    let physical_memory_end = memory_map
+        .iter()
+        .map(|r| r.range.end_addr())
+        .max()
+        .map_or(physical_memory_start, |end| physical_memory_start + end);
+
+    // the walk borrows the page tables, so changing them has to wait
+    let mut ranges = Vec::new();
+    walk::walk(|range| ranges.push(range));
+
+    for range in ranges {
+        let (insert, remove) = if contains(&range, text) {
+            assert!(
+                !contains(&range, data),
+                "kernel code and data are mapped as one range"
+            );
+            (
+                PageTableFlags::empty(),
+                PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            )
+        } else if is_kernel_frame(memory_map, &range)
+            || (range.start >= physical_memory_start && range.start < physical_memory_end)
+        {
+            (PageTableFlags::NO_EXECUTE, PageTableFlags::empty())
+        } else {
+            continue;
+        };
+        if (range.flags | insert) - remove != range.flags {
+            update_flags(&range, insert, remove, mapper)?;
+        }
+    }
+    Ok(())
+}
+
+fn contains(range: &MappedRange, addr: VirtAddr) -> bool {
+    range.start <= addr && addr - range.start < range.size
+}
+
+/// Returns whether any frame that the given range maps belongs to the kernel
+/// image or the boot stack.
+fn is_kernel_frame(memory_map: &MemoryMap, range: &MappedRange) -> bool {
+    let start = range.phys_start.as_u64();
+    let end = start + range.size;
+    memory_map.iter().any(|r| {
+        let kernel = r.region_type == MemoryRegionType::Kernel
+            || r.region_type == MemoryRegionType::KernelStack;
+        kernel && r.range.start_addr() < end && start < r.range.end_addr()
+    })
+}
+
+/// Inserts and removes the given flags in the entries that map the pages of
+/// the given range.
+///
+/// The other flags of each entry are kept as they are, so flags that the range
+/// only has through a higher level table are not copied into the entries.
+fn update_flags(
+    range: &MappedRange,
+    insert: PageTableFlags,
+    remove: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+) -> Result<(), FlagUpdateError> {
+    let page_size = range.page_size.bytes();
+    for i in 0..range.size / page_size {
+        let addr = range.start + i * page_size;
+        let entry_flags = walk::translate(addr)
+            .expect("range is no longer mapped")
+            .entry_flags;
+        let flags = (entry_flags | insert) - remove;
+        match range.page_size {
+            MappingSize::Size4KiB => {
+                let page: Page<Size4KiB> = Page::containing_address(addr);
+                mapper.update_flags(page, flags)?.flush();
+            }
+            MappingSize::Size2MiB => {
+                let page: Page<Size2MiB> = Page::containing_address(addr);
+                mapper.update_flags(page, flags)?.flush();
+            }
+            MappingSize::Size1GiB => {
+                let page: Page<Size1GiB> = Page::containing_address(addr);
+                mapper.update_flags(page, flags)?.flush();
+            }
+        }
+    }
+    Ok(())
+}
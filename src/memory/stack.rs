@@ -0,0 +1,130 @@
+use super::guard::{self, GuardPosition};
+use super::vmm::{self, VirtualRegion, VmmError};
+use super::{FRAME_ALLOCATOR, MAPPER};
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, PageSize, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+/// A kernel stack with an unmapped guard page below it.
+///
+/// Stack overflows hit the guard page, which is registered under the stack's
+/// name so that the page fault handler can report it. The stack is unmapped
+/// and its frames are returned to `FRAME_ALLOCATOR` when the handle is dropped,
+/// so the owner must keep the handle alive as long as the stack is in use.
+#[derive(Debug)]
+pub struct StackBounds {
+    region: VirtualRegion,
+    start: VirtAddr,
+    end: VirtAddr,
+}
+
+impl StackBounds {
+    /// Allocates a stack of `size` bytes through `MAPPER` and `FRAME_ALLOCATOR`.
+    pub fn alloc(name: &'static str, size: u64) -> Result<Self, VmmError> {
+        let mut vmm = vmm::VMM.lock();
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+            (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+            _ => return Err(VmmError::Uninitialized),
+        };
+        let region = vmm.allocate(name, size + Size4KiB::SIZE, Size4KiB::SIZE)?;
+        Self::map(name, region, mapper, frame_allocator).map_err(|err| {
+            vmm.free(region.start).expect("stack region was just allocated");
+            err
+        })
+    }
+
+    /// Allocates a stack of `size` bytes with the given mapper and frame
+    /// allocator, for use before `memory::init_globals` is called.
+    ///
+    /// The handle must not be dropped before `init_globals` is called, since
+    /// the frames are returned to `FRAME_ALLOCATOR`.
+    pub fn alloc_with(
+        name: &'static str,
+        size: u64,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> Result<Self, VmmError> {
+        let region = vmm::VMM
+            .lock()
+            .allocate(name, size + Size4KiB::SIZE, Size4KiB::SIZE)?;
+        Self::map(name, region, mapper, frame_allocator).map_err(|err| {
+            vmm::VMM.lock().free(region.start).expect("stack region was just allocated");
+            err
+        })
+    }
+
+    /// Maps the stack pages of the given region, which starts with the guard
+    /// page.
+    ///
+    /// If mapping fails, the pages that were already mapped are unmapped again
+    /// and their frames are returned to `frame_allocator`.
+    fn map(
+        name: &'static str,
+        region: VirtualRegion,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> Result<Self, VmmError> {
+        let start = region.start + Size4KiB::SIZE;
+        let end = region.end();
+
+        let flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        if let Err(err) = vmm::map_pages(start, end - start, flags, mapper, frame_allocator) {
+            // the stack is partially mapped, so give back what was mapped
+            unsafe { vmm::unmap_pages(start, end - start, mapper, frame_allocator) };
+            return Err(err.into());
+        }
+
+        guard::register(name, GuardPosition::Below, region.start, start);
+        Ok(StackBounds { region, start, end })
+    }
+
+    /// Returns the lowest address of the stack.
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// Returns the top of the stack, i.e. the address that is loaded into the
+    /// stack pointer.
+    pub fn end(&self) -> VirtAddr {
+        self.end
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Returns the start of the guard page.
+    pub fn guard_start(&self) -> VirtAddr {
+        self.region.start
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+impl Drop for StackBounds {
+    fn drop(&mut self) {
+        let mut vmm = vmm::VMM.lock();
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        // a stack can only be dropped once `init_globals` was called, see
+        // `alloc_with`
+        let mapper = mapper.as_mut().expect("MAPPER is not initialized");
+        let frame_allocator = frame_allocator
+            .as_mut()
+            .expect("FRAME_ALLOCATOR is not initialized");
+
+        guard::unregister(self.region.start);
+        // the frames were mapped by `map` and the owner guarantees that the
+        // stack is no longer in use
+        unsafe { vmm::unmap_pages(self.start, self.size(), mapper, frame_allocator) };
+        vmm.free(self.region.start).expect("stack was not allocated");
+    }
+}
@@ -0,0 +1,406 @@
+use super::{FRAME_ALLOCATOR, MAPPER};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageSize,
+        PageTableFlags, Size2MiB, Size4KiB, UnusedPhysFrame,
+    },
+    VirtAddr,
+};
+
+/// The start of the virtual range that the kernel hands out for heaps, stacks
+/// and MMIO.
+pub const KERNEL_SPACE_START: u64 = 0x_4444_0000_0000;
+
+/// The end of the virtual range that the kernel hands out.
+pub const KERNEL_SPACE_END: u64 = 0x_6000_0000_0000;
+
+/// The maximum number of regions that can be in use at the same time.
+const MAX_REGIONS: usize = 128;
+
+/// The kernel's virtual memory manager.
+///
+/// When `VMM` is needed together with `MAPPER` or `FRAME_ALLOCATOR`, it must be
+/// locked first.
+pub static VMM: Mutex<VirtualMemoryManager> =
+    Mutex::new(VirtualMemoryManager::new(KERNEL_SPACE_START, KERNEL_SPACE_END));
+
+/// A range of virtual memory that is in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtualRegion {
+    /// The subsystem that uses the region.
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub size: u64,
+}
+
+impl VirtualRegion {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+}
+
+#[derive(Debug)]
+pub enum VmmError {
+    /// No free range of the requested size is left.
+    OutOfVirtualSpace,
+    /// The requested range overlaps a region that is in use or lies outside of
+    /// the managed range.
+    Unavailable,
+    /// No region starts at the given address.
+    NotFound,
+    /// The maximum number of regions is in use.
+    TooManyRegions,
+    /// `memory::init_globals` was not called yet.
+    Uninitialized,
+    MapFailed(MapToError<Size4KiB>),
+    HugeMapFailed(MapToError<Size2MiB>),
+}
+
+impl From<MapToError<Size4KiB>> for VmmError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        VmmError::MapFailed(err)
+    }
+}
+
+impl From<MapToError<Size2MiB>> for VmmError {
+    fn from(err: MapToError<Size2MiB>) -> Self {
+        VmmError::HugeMapFailed(err)
+    }
+}
+
+/// Keeps track of the virtual ranges that are in use in the managed range.
+///
+/// Regions are page aligned and kept sorted by start address.
+pub struct VirtualMemoryManager {
+    regions: [Option<VirtualRegion>; MAX_REGIONS],
+    len: usize,
+    start: u64,
+    end: u64,
+}
+
+impl VirtualMemoryManager {
+    /// Creates a manager for the virtual range `start..end`.
+    pub const fn new(start: u64, end: u64) -> Self {
+        VirtualMemoryManager {
+            regions: [None; MAX_REGIONS],
+            len: 0,
+            start,
+            end,
+        }
+    }
+
+    /// Marks the given range as used.
+    ///
+    /// This is for subsystems that must live at a fixed address.
+    pub fn reserve(
+        &mut self,
+        name: &'static str,
+        start: VirtAddr,
+        size: u64,
+    ) -> Result<VirtualRegion, VmmError> {
+        let end = align_up(start.as_u64() + size, Size4KiB::SIZE);
+        let start = start.align_down(Size4KiB::SIZE).as_u64();
+        if start < self.start || end > self.end {
+            return Err(VmmError::Unavailable);
+        }
+        let index = self.regions().position(|r| r.start.as_u64() >= start).unwrap_or(self.len);
+        let overlaps_previous = index > 0 && self.region(index - 1).end().as_u64() > start;
+        let overlaps_next = index < self.len && self.region(index).start.as_u64() < end;
+        if overlaps_previous || overlaps_next {
+            return Err(VmmError::Unavailable);
+        }
+        self.insert(index, name, start, end)
+    }
+
+    /// Finds a free range of `size` bytes whose start is aligned to `align` and
+    /// marks it as used.
+    ///
+    /// `align` must be a power of two; it is at least the page size.
+    pub fn allocate(
+        &mut self,
+        name: &'static str,
+        size: u64,
+        align: u64,
+    ) -> Result<VirtualRegion, VmmError> {
+        let align = align.max(Size4KiB::SIZE);
+        let size = align_up(size, Size4KiB::SIZE);
+
+        // first fit: try the gap before each region and the gap after the last one
+        let mut gap_start = self.start;
+        for index in 0..=self.len {
+            let gap_end = if index < self.len {
+                self.region(index).start.as_u64()
+            } else {
+                self.end
+            };
+            let start = align_up(gap_start, align);
+            if start.checked_add(size).map_or(false, |end| end <= gap_end) {
+                return self.insert(index, name, start, start + size);
+            }
+            if index < self.len {
+                gap_start = self.region(index).end().as_u64();
+            }
+        }
+        Err(VmmError::OutOfVirtualSpace)
+    }
+
+    /// Marks the region that starts at the given address as free.
+    ///
+    /// The caller is responsible for unmapping the region first.
+    pub fn free(&mut self, start: VirtAddr) -> Result<VirtualRegion, VmmError> {
+        let index = self
+            .regions()
+            .position(|r| r.start == start)
+            .ok_or(VmmError::NotFound)?;
+        let region = self.region(index);
+        for i in index..self.len - 1 {
+            self.regions[i] = self.regions[i + 1];
+        }
+        self.len -= 1;
+        self.regions[self.len] = None;
+        Ok(region)
+    }
+
+    /// Returns the region that contains the given address.
+    pub fn find(&self, addr: VirtAddr) -> Option<VirtualRegion> {
+        self.regions().find(|r| r.contains(addr))
+    }
+
+    /// Returns an iterator over all regions in use, sorted by address.
+    pub fn regions(&self) -> impl Iterator<Item = VirtualRegion> + '_ {
+        self.regions[..self.len].iter().flatten().copied()
+    }
+
+    fn region(&self, index: usize) -> VirtualRegion {
+        self.regions[index].expect("region index out of bounds")
+    }
+
+    fn insert(
+        &mut self,
+        index: usize,
+        name: &'static str,
+        start: u64,
+        end: u64,
+    ) -> Result<VirtualRegion, VmmError> {
+        if self.len == MAX_REGIONS {
+            return Err(VmmError::TooManyRegions);
+        }
+        for i in (index..self.len).rev() {
+            self.regions[i + 1] = self.regions[i];
+        }
+        let region = VirtualRegion {
+            name,
+            start: VirtAddr::new(start),
+            size: end - start,
+        };
+        self.regions[index] = Some(region);
+        self.len += 1;
+        Ok(region)
+    }
+}
+
+/// Maps the pages of the given virtual range to newly allocated frames.
+pub fn map_pages(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let start_page = Page::containing_address(start);
+    let end_page = Page::containing_address(start + size - 1u64);
+    for page in Page::range_inclusive(start_page, end_page) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+    }
+    Ok(())
+}
+
+/// Maps the given virtual range to newly allocated frames, using 2 MiB pages
+/// where possible.
+///
+/// A 2 MiB page is used for every part of the range that is 2 MiB aligned and
+/// at least 2 MiB large, as long as the frame allocator has a free 2 MiB frame.
+/// Everything else is mapped with 4 KiB pages. Ranges mapped this way must be
+/// unmapped with `unmap_range`.
+pub fn map_range<M, A>(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut M,
+    frame_allocator: &mut A,
+) -> Result<(), VmmError>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+    A: FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB>,
+{
+    let end = start + size;
+    let mut addr = start.align_down(Size4KiB::SIZE);
+    while addr < end {
+        if addr.is_aligned(Size2MiB::SIZE) && end - addr >= Size2MiB::SIZE {
+            let frame: Option<UnusedPhysFrame<Size2MiB>> = frame_allocator.allocate_frame();
+            if let Some(frame) = frame {
+                let page: Page<Size2MiB> = Page::containing_address(addr);
+                mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+                addr += Size2MiB::SIZE;
+                continue;
+            }
+            // physical memory is too fragmented, fall back to 4 KiB pages
+        }
+        let frame = FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator)
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let page: Page = Page::containing_address(addr);
+        mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+        addr += Size4KiB::SIZE;
+    }
+    Ok(())
+}
+
+/// Unmaps a range that was mapped through `map_range` and frees its frames.
+///
+/// Pages that are not mapped are skipped.
+///
+/// This function is unsafe because the caller must guarantee that the frames
+/// were allocated from `frame_allocator` and are not mapped anywhere else.
+pub unsafe fn unmap_range<M, A>(start: VirtAddr, size: u64, mapper: &mut M, frame_allocator: &mut A)
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+    A: FrameDeallocator<Size4KiB> + FrameDeallocator<Size2MiB>,
+{
+    let end = start + size;
+    let mut addr = start.align_down(Size4KiB::SIZE);
+    while addr < end {
+        if addr.is_aligned(Size2MiB::SIZE) && end - addr >= Size2MiB::SIZE {
+            let page: Page<Size2MiB> = Page::containing_address(addr);
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.flush();
+                frame_allocator.deallocate_frame(UnusedPhysFrame::new(frame));
+                addr += Size2MiB::SIZE;
+                continue;
+            }
+        }
+        let page: Page = Page::containing_address(addr);
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            frame_allocator.deallocate_frame(UnusedPhysFrame::new(frame));
+        }
+        addr += Size4KiB::SIZE;
+    }
+}
+
+/// Unmaps the pages of the given virtual range and frees their frames.
+///
+/// Pages that are not mapped are skipped, which includes pages that are part
+/// of a 2 MiB mapping. Use `unmap_range` for ranges that contain those.
+///
+/// This function is unsafe because the caller must guarantee that the frames
+/// were allocated from `frame_allocator` and are not mapped anywhere else.
+pub unsafe fn unmap_pages(
+    start: VirtAddr,
+    size: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let start_page = Page::containing_address(start);
+    let end_page = Page::containing_address(start + size - 1u64);
+    for page in Page::range_inclusive(start_page, end_page) {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            frame_allocator.deallocate_frame(UnusedPhysFrame::new(frame));
+        }
+    }
+}
+
+/// Allocates a virtual region of `size` bytes and maps it to new frames.
+pub fn alloc_mapped(
+    name: &'static str,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<VirtualRegion, VmmError> {
+    let mut vmm = VMM.lock();
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return Err(VmmError::Uninitialized),
+    };
+
+    let region = vmm.allocate(name, size, Size4KiB::SIZE)?;
+    if let Err(err) = map_pages(region.start, region.size, flags, mapper, frame_allocator) {
+        // the region is partially mapped, so give back what was mapped
+        unsafe { unmap_pages(region.start, region.size, mapper, frame_allocator) };
+        vmm.free(region.start)?;
+        return Err(err.into());
+    }
+    Ok(region)
+}
+
+/// Unmaps and frees a region that was allocated through `alloc_mapped`.
+///
+/// This function is unsafe because the caller must guarantee that the memory
+/// of the region is no longer used.
+pub unsafe fn free_mapped(start: VirtAddr) -> Result<(), VmmError> {
+    let mut vmm = VMM.lock();
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return Err(VmmError::Uninitialized),
+    };
+
+    let region = vmm.free(start)?;
+    unmap_pages(region.start, region.size, mapper, frame_allocator);
+    Ok(())
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_allocate_first_fit() {
+    serial_print!("test_allocate_first_fit... ");
+    let mut vmm = VirtualMemoryManager::new(0x10_0000, 0x20_0000);
+    let a = vmm.allocate("a", 0x1000, 0x1000).unwrap();
+    let b = vmm.allocate("b", 0x1800, 0x1000).unwrap();
+    assert_eq!(a.start.as_u64(), 0x10_0000);
+    assert_eq!(b.start.as_u64(), 0x10_1000);
+    assert_eq!(b.size, 0x2000);
+
+    // the freed gap is reused by an allocation that fits into it
+    vmm.free(a.start).unwrap();
+    let c = vmm.allocate("c", 0x1000, 0x1000).unwrap();
+    assert_eq!(c.start, a.start);
+
+    let d = vmm.allocate("d", 0x1000, 0x10_0000).unwrap_err();
+    assert!(matches!(d, VmmError::OutOfVirtualSpace));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_reserve_rejects_overlap() {
+    serial_print!("test_reserve_rejects_overlap... ");
+    let mut vmm = VirtualMemoryManager::new(0x10_0000, 0x20_0000);
+    vmm.reserve("fixed", VirtAddr::new(0x14_0000), 0x4000).unwrap();
+    assert!(vmm.reserve("overlap", VirtAddr::new(0x14_3000), 0x1000).is_err());
+    assert!(vmm.reserve("outside", VirtAddr::new(0x20_0000), 0x1000).is_err());
+
+    let region = vmm.find(VirtAddr::new(0x14_2345)).unwrap();
+    assert_eq!(region.name, "fixed");
+    assert!(vmm.find(VirtAddr::new(0x14_4000)).is_none());
+
+    // allocations skip the reserved range
+    let big = vmm.allocate("big", 0x5_0000, 0x1000).unwrap();
+    assert_eq!(big.start.as_u64(), 0x14_4000);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,229 @@
+use super::{active_level_4_table_ptr, physical_memory_offset};
+use crate::serial_println;
+use x86_64::{
+    structures::paging::{PageTable, PageTableFlags},
+    PhysAddr, VirtAddr,
+};
+
+/// The size of the page through which a range is mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl MappingSize {
+    pub fn bytes(self) -> u64 {
+        match self {
+            MappingSize::Size4KiB => 4096,
+            MappingSize::Size2MiB => 2 * 1024 * 1024,
+            MappingSize::Size1GiB => 1024 * 1024 * 1024,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            MappingSize::Size4KiB => "4K",
+            MappingSize::Size2MiB => "2M",
+            MappingSize::Size1GiB => "1G",
+        }
+    }
+}
+
+/// A virtually and physically contiguous range of pages with the same flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub phys_start: PhysAddr,
+    pub page_size: MappingSize,
+    /// The effective flags of the mapping, i.e. `WRITABLE` and
+    /// `USER_ACCESSIBLE` are only set if all levels allow the access and
+    /// `NO_EXECUTE` is set if any level forbids execution.
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange {
+    /// Prints the range over the serial interface.
+    pub fn dump(&self) {
+        let flag = |flag, set, unset| if self.flags.contains(flag) { set } else { unset };
+        serial_println!(
+            "{:#018x}-{:#018x} -> {:#014x} {:>10} KiB {}{}{}{} {}",
+            self.start.as_u64(),
+            self.start.as_u64() + (self.size - 1),
+            self.phys_start.as_u64(),
+            self.size / 1024,
+            flag(PageTableFlags::PRESENT, 'P', '-'),
+            flag(PageTableFlags::WRITABLE, 'W', 'R'),
+            flag(PageTableFlags::USER_ACCESSIBLE, 'U', 'K'),
+            flag(PageTableFlags::NO_EXECUTE, '-', 'X'),
+            self.page_size.label(),
+        );
+    }
+}
+
+/// The result of a successful `translate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    pub phys_addr: PhysAddr,
+    pub page_size: MappingSize,
+    /// The effective flags of the mapping, see `MappedRange::flags`.
+    pub flags: PageTableFlags,
+    /// The flags of the entry that maps the page itself.
+    pub entry_flags: PageTableFlags,
+}
+
+/// Returns the flags with which `combine` starts at the level 4 table.
+fn root_flags() -> PageTableFlags {
+    PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE
+}
+
+/// Calls `f` for every mapped range of the active page table, in address order.
+///
+/// Adjacent pages are merged into one range if they are physically contiguous
+/// and have the same size and flags.
+///
+/// The table is read without locking `MAPPER`, so mappings that change during
+/// the walk may be reported inconsistently.
+pub fn walk(f: impl FnMut(MappedRange)) {
+    let level_4_table = unsafe { &*active_level_4_table_ptr(physical_memory_offset()) };
+    let mut runs = Runs { current: None, f };
+    walk_table(level_4_table, 4, 0, root_flags(), &mut runs);
+    runs.finish();
+}
+
+/// Prints all mapped ranges of the active page table over the serial interface.
+pub fn dump() {
+    serial_println!("virtual range                            physical            size       flags");
+    walk(|range| range.dump());
+}
+
+/// Translates the given virtual address through the active page table.
+///
+/// Returns `None` if the address is not mapped.
+pub fn translate(addr: VirtAddr) -> Option<Translation> {
+    let mut table = unsafe { &*active_level_4_table_ptr(physical_memory_offset()) };
+    let mut flags = root_flags();
+    let indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    for (i, &index) in indexes.iter().enumerate() {
+        let level = 4 - i;
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        flags = combine(flags, entry.flags());
+        let page_size = match level {
+            3 if entry.flags().contains(PageTableFlags::HUGE_PAGE) => Some(MappingSize::Size1GiB),
+            2 if entry.flags().contains(PageTableFlags::HUGE_PAGE) => Some(MappingSize::Size2MiB),
+            1 => Some(MappingSize::Size4KiB),
+            _ => None,
+        };
+        if let Some(page_size) = page_size {
+            let offset = addr.as_u64() & (page_size.bytes() - 1);
+            return Some(Translation {
+                phys_addr: entry.addr() + offset,
+                page_size,
+                flags,
+                entry_flags: entry.flags(),
+            });
+        }
+        table = unsafe { table_at(entry.addr()) };
+    }
+    unreachable!("level 1 entries always map a page")
+}
+
+/// Collects mapped pages into ranges.
+struct Runs<F> {
+    current: Option<MappedRange>,
+    f: F,
+}
+
+impl<F: FnMut(MappedRange)> Runs<F> {
+    fn push(&mut self, range: MappedRange) {
+        if let Some(current) = self.current.as_mut() {
+            let contiguous = current.start.as_u64().wrapping_add(current.size) == range.start.as_u64()
+                && current.phys_start + current.size == range.phys_start;
+            if contiguous && current.flags == range.flags && current.page_size == range.page_size {
+                current.size += range.size;
+                return;
+            }
+        }
+        self.finish();
+        self.current = Some(range);
+    }
+
+    fn finish(&mut self) {
+        if let Some(current) = self.current.take() {
+            (self.f)(current);
+        }
+    }
+}
+
+fn walk_table<F: FnMut(MappedRange)>(
+    table: &PageTable,
+    level: usize,
+    base: u64,
+    parent_flags: PageTableFlags,
+    runs: &mut Runs<F>,
+) {
+    let entry_size = 4096u64 << (9 * (level - 1));
+    for (index, entry) in table.iter().enumerate() {
+        let entry_flags = entry.flags();
+        if !entry_flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let start = canonical(base + index as u64 * entry_size);
+        let flags = combine(parent_flags, entry_flags);
+        let page_size = match level {
+            3 if entry_flags.contains(PageTableFlags::HUGE_PAGE) => Some(MappingSize::Size1GiB),
+            2 if entry_flags.contains(PageTableFlags::HUGE_PAGE) => Some(MappingSize::Size2MiB),
+            1 => Some(MappingSize::Size4KiB),
+            _ => None,
+        };
+        match page_size {
+            Some(page_size) => runs.push(MappedRange {
+                start: VirtAddr::new(start),
+                size: entry_size,
+                phys_start: entry.addr(),
+                page_size,
+                // the CPU updates these on access, so they would prevent merging
+                flags: flags - PageTableFlags::ACCESSED - PageTableFlags::DIRTY,
+            }),
+            None => {
+                let next = unsafe { table_at(entry.addr()) };
+                walk_table(next, level - 1, start, flags, runs);
+            }
+        }
+    }
+}
+
+/// Combines the flags of a parent entry with the flags of a child entry.
+///
+/// `WRITABLE` and `USER_ACCESSIBLE` must be allowed by all levels, while
+/// `NO_EXECUTE` on any level applies to the whole subtree.
+fn combine(parent: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+    let restricting = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let mut flags = entry - restricting;
+    flags |= entry & parent & restricting;
+    flags |= parent & PageTableFlags::NO_EXECUTE;
+    flags
+}
+
+/// Returns the page table in the given frame.
+///
+/// This function is unsafe because the caller must guarantee that the frame
+/// contains a page table.
+unsafe fn table_at(addr: PhysAddr) -> &'static PageTable {
+    &*(physical_memory_offset() + addr.as_u64()).as_ptr()
+}
+
+/// Sign extends bit 47 of the given address.
+fn canonical(addr: u64) -> u64 {
+    ((addr << 16) as i64 >> 16) as u64
+}
@@ -1,6 +1,18 @@
+use crate::interrupts::irq;
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
 use lazy_static::lazy_static;
 use spin::Mutex;
 use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+
+/// The line status register of COM1.
+const LINE_STATUS_PORT: u16 = 0x3F8 + 5;
+/// Set in the line status register while a received byte can be read.
+const DATA_READY: u8 = 1;
+
+/// The bytes that the host sent and that `read_byte` didn't return yet.
+static INPUT: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
@@ -10,6 +22,37 @@ lazy_static! {
     };
 }
 
+/// Buffers the bytes that the host sends over COM1, so that `read_byte` can
+/// return them.
+///
+/// Allocates the input buffer, so the heap must be initialized.
+pub fn init_input() {
+    INPUT
+        .try_init_once(|| ArrayQueue::new(256))
+        .expect("serial::init_input should only be called once");
+    irq::register(irq::COM1, interrupt_handler)
+        .expect("failed to register the serial interrupt handler");
+}
+
+/// Returns the oldest byte that the host sent, if any.
+pub fn read_byte() -> Option<u8> {
+    INPUT.try_get().ok()?.pop().ok()
+}
+
+fn interrupt_handler() {
+    let mut line_status = Port::<u8>::new(LINE_STATUS_PORT);
+    // `_print` only holds the lock with interrupts disabled
+    let mut serial = SERIAL1.lock();
+    // the interrupt is edge triggered, so all received bytes must be read
+    while unsafe { line_status.read() } & DATA_READY != 0 {
+        let byte = serial.receive();
+        if let Ok(queue) = INPUT.try_get() {
+            // drop the input if nobody reads it
+            let _ = queue.push(byte);
+        }
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
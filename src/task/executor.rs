@@ -1,4 +1,4 @@
-use super::{Task, TaskId};
+use super::{timer, Task, TaskId};
 use alloc::{collections::{BTreeMap, VecDeque}, sync::Arc};
 use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
@@ -48,6 +48,7 @@ impl Executor {
 
     pub fn run(&mut self) -> ! {
         loop {
+            timer::wake_expired();
             self.wake_tasks();
             self.run_ready_tasks();
             self.sleep_if_idle();
@@ -104,7 +105,7 @@ impl Executor {
         }
 
         interrupts::disable();
-        if self.wake_queue.is_empty() {
+        if self.wake_queue.is_empty() && !timer::has_expired() {
             enable_interrupts_and_hlt();
         } else {
             interrupts::enable();
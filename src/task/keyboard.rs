@@ -6,6 +6,7 @@ use futures_util::stream::{Stream, StreamExt};
 use futures_util::task::AtomicWaker;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 
+use crate::interrupts::irq;
 use crate::print;
 use crate::println;
 
@@ -50,10 +51,26 @@ impl Stream for ScancodeStream {
     }
 }
 
+/// Registers the handler that queues the scancodes of the keyboard.
+///
+/// Called by `crate::init` before interrupts are enabled.
+pub fn init() {
+    irq::register(irq::KEYBOARD, interrupt_handler)
+        .expect("failed to register the keyboard interrupt handler");
+}
+
+fn interrupt_handler() {
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+    add_scancode(scancode);
+}
+
 /// Called by the keyboard interrupt handler
 ///
 /// Must not block or allocate.
-pub(crate) fn add_scancode(scancode: u8) {
+fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if let Err(_) = queue.push(scancode) {
             println!("WARNING: scancode queue full; dropping keyboard input")
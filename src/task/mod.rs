@@ -6,6 +6,7 @@ use core::sync::atomic::{AtomicU64, Ordering};
 pub mod executor;
 pub mod keyboard;
 pub mod simple_executor;
+pub mod timer;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(u64);
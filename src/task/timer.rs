@@ -0,0 +1,251 @@
+use alloc::collections::BTreeMap;
+use core::{future::Future, pin::Pin, time::Duration};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use crate::interrupts::{irq, pit};
+use futures_util::task::AtomicWaker;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// The number of timer interrupts since boot.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// The wakers of all pending `Sleep`s, ordered by their deadline tick.
+    ///
+    /// The second part of the key makes entries with the same deadline unique.
+    /// The queue is never touched from the interrupt handler, which only sees
+    /// the earliest entry through `EARLIEST_DEADLINE` and `EARLIEST_WAKER`.
+    static ref DEADLINES: Mutex<BTreeMap<(u64, u64), Waker>> = Mutex::new(BTreeMap::new());
+}
+
+/// The tick of the first entry of `DEADLINES`, or `u64::max_value()` if there
+/// is none or it was woken already.
+static EARLIEST_DEADLINE: AtomicU64 = AtomicU64::new(u64::max_value());
+
+/// A clone of the waker of the first entry of `DEADLINES`, which `tick` wakes
+/// once its deadline is reached.
+///
+/// `DEADLINES` keeps its own clone, so the interrupt handler never drops the
+/// last reference to a waker.
+static EARLIEST_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Registers the handler that counts the timer interrupts.
+///
+/// Called by `crate::init` before interrupts are enabled.
+pub fn init() {
+    irq::register(irq::TIMER, tick).expect("failed to register the timer interrupt handler");
+}
+
+/// Called by the timer interrupt handler
+///
+/// Must not block or allocate.
+fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    if now >= EARLIEST_DEADLINE.load(Ordering::Acquire) {
+        // the woken task installs the next deadline through `update_deadlines`
+        EARLIEST_DEADLINE.store(u64::max_value(), Ordering::Release);
+        EARLIEST_WAKER.wake();
+    }
+}
+
+/// Returns the number of timer ticks since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the time since boot with the resolution of a timer tick.
+///
+/// Use `time::Instant` for a finer resolution.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(ticks() * pit::tick_nanos())
+}
+
+/// Returns the number of ticks that cover at least the given duration.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos = duration.as_nanos();
+    let tick_nanos = u128::from(pit::tick_nanos());
+    let ticks = (nanos + tick_nanos - 1) / tick_nanos;
+    if ticks > u128::from(u64::max_value()) {
+        u64::max_value()
+    } else {
+        ticks as u64
+    }
+}
+
+/// Wakes the tasks whose deadline has passed.
+///
+/// The timer interrupt wakes the task of the earliest deadline on its own, so
+/// this is only an optimization for executors: called before the woken tasks
+/// are polled, it also wakes the tasks whose deadlines passed in the same tick.
+pub(crate) fn wake_expired() {
+    update_deadlines(|_| ());
+}
+
+/// Changes the pending deadlines through `f`, wakes the expired ones and hands
+/// the earliest remaining one to the timer interrupt handler.
+fn update_deadlines<R>(f: impl FnOnce(&mut BTreeMap<(u64, u64), Waker>) -> R) -> R {
+    let mut deadlines = DEADLINES.lock();
+    // keeps `tick` from waking `EARLIEST_WAKER` while the entries change, so
+    // that it never holds the last clone of a waker
+    EARLIEST_DEADLINE.store(u64::max_value(), Ordering::Release);
+    let result = f(&mut deadlines);
+
+    let now = ticks();
+    while let Some(&key) = deadlines.keys().next() {
+        if key.0 > now {
+            break;
+        }
+        if let Some(waker) = deadlines.remove(&key) {
+            waker.wake();
+        }
+    }
+    match deadlines.iter().next() {
+        Some((&(deadline, _), waker)) => {
+            EARLIEST_WAKER.register(waker);
+            EARLIEST_DEADLINE.store(deadline, Ordering::Release);
+        }
+        None => drop(EARLIEST_WAKER.take()),
+    }
+    result
+}
+
+/// Returns whether a deadline has passed that `wake_expired` didn't handle
+/// yet.
+///
+/// Called by the executor with interrupts disabled, so it must not block.
+pub(crate) fn has_expired() -> bool {
+    match DEADLINES.try_lock() {
+        Some(deadlines) => deadlines.keys().next().map_or(false, |key| key.0 <= ticks()),
+        // the lock is only held by task code, which isn't running
+        None => false,
+    }
+}
+
+/// A future that completes once the given tick is reached.
+pub struct Sleep {
+    deadline: u64,
+    id: u64,
+    registered: bool
+}
+
+impl Sleep {
+    fn new(deadline: u64) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Sleep {
+            deadline,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            registered: false
+        }
+    }
+
+    /// Returns the tick at which the sleep completes.
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    fn unregister(&mut self) {
+        if self.registered {
+            let key = (self.deadline, self.id);
+            update_deadlines(|deadlines| deadlines.remove(&key));
+            self.registered = false;
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if ticks() >= self.deadline {
+            self.unregister();
+            return Poll::Ready(());
+        }
+        let (key, waker) = ((self.deadline, self.id), cx.waker().clone());
+        update_deadlines(|deadlines| deadlines.insert(key, waker));
+        self.registered = true;
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// Waits for at least the given duration.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(ticks() + duration_to_ticks(duration))
+}
+
+/// Waits until the tick counter reaches `deadline`.
+pub fn sleep_until(deadline: u64) -> Sleep {
+    Sleep::new(deadline)
+}
+
+/// The error of a `Timeout` whose deadline passed first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// A future that completes with the output of the inner future or with
+/// `Elapsed` once the deadline is reached, whichever comes first.
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // the inner future is never moved out of the pinned `Timeout`
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending
+        }
+    }
+}
+
+/// Runs `future` for at most the given duration.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration)
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_duration_to_ticks() {
+    serial_print!("test_duration_to_ticks... ");
+    assert_eq!(duration_to_ticks(Duration::from_secs(0)), 0);
+    assert_eq!(duration_to_ticks(Duration::from_nanos(1)), 1);
+    let tick = pit::tick_nanos();
+    assert_eq!(duration_to_ticks(Duration::from_nanos(tick)), 1);
+    assert_eq!(duration_to_ticks(Duration::from_nanos(tick + 1)), 2);
+    assert_eq!(duration_to_ticks(Duration::from_nanos(100 * tick)), 100);
+    // a second is rounded up to whole ticks
+    let ticks = duration_to_ticks(Duration::from_secs(1));
+    assert!((ticks - 1) * tick < 1_000_000_000);
+    assert!(ticks * tick >= 1_000_000_000);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_ticks_advance() {
+    serial_print!("test_ticks_advance... ");
+    let start = ticks();
+    while ticks() == start {
+        x86_64::instructions::hlt();
+    }
+    assert!(uptime() >= Duration::from_nanos(pit::tick_nanos()));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,185 @@
+use crate::interrupts::pit;
+use crate::task::timer;
+use core::arch::x86_64::_rdtsc;
+use core::convert::TryFrom;
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub use core::time::Duration;
+
+/// The number of timer ticks over which `calibrate` measures the TSC.
+const CALIBRATION_TICKS: u64 = 5;
+
+/// The TSC rate in Hz, or zero if the TSC is not calibrated yet.
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// The TSC value at the end of the calibration.
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+/// The time since boot in nanoseconds at the end of the calibration.
+static NANOS_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// A point in time, measured in nanoseconds since the timer was started.
+///
+/// Instants are taken from the TSC once `calibrate` was called, and from the
+/// timer tick counter before that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        let frequency = TSC_FREQUENCY.load(Ordering::Acquire);
+        if frequency == 0 {
+            return Instant(timer::ticks() * pit::tick_nanos());
+        }
+        let cycles = read_tsc().saturating_sub(TSC_BASE.load(Ordering::Relaxed));
+        let nanos = u128::from(cycles) * 1_000_000_000 / u128::from(frequency);
+        Instant(NANOS_BASE.load(Ordering::Relaxed) + nanos as u64)
+    }
+
+    /// Returns the time that passed since `earlier`, or zero if `earlier` is
+    /// later than `self`.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Returns the time between the start of the timer and this instant.
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.0)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        u64::try_from(duration.as_nanos())
+            .ok()
+            .and_then(|nanos| self.0.checked_add(nanos))
+            .map(Instant)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    /// Saturates at the start of the timer.
+    fn sub(self, duration: Duration) -> Instant {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::max_value());
+        Instant(self.0.saturating_sub(nanos))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Returns the time since the timer was started.
+pub fn uptime() -> Duration {
+    Instant::now().since_boot()
+}
+
+/// Measures the TSC rate against the timer interrupt.
+///
+/// Needs the PIT to be programmed and interrupts to be enabled, and blocks
+/// for `CALIBRATION_TICKS` timer ticks. `crate::init` doesn't call it, so
+/// kernels that need precise timing must call it afterwards.
+pub fn calibrate() {
+    // start at a tick boundary so that the measured ticks are complete
+    let start_tick = wait_for_tick(timer::ticks());
+    let start = read_tsc();
+    let end_tick = start_tick + CALIBRATION_TICKS;
+    while timer::ticks() < end_tick {
+        x86_64::instructions::hlt();
+    }
+    let end = read_tsc();
+
+    let nanos = CALIBRATION_TICKS * pit::tick_nanos();
+    let frequency = u128::from(end - start) * 1_000_000_000 / u128::from(nanos);
+    TSC_BASE.store(end, Ordering::Relaxed);
+    NANOS_BASE.store(end_tick * pit::tick_nanos(), Ordering::Relaxed);
+    TSC_FREQUENCY.store(frequency as u64, Ordering::Release);
+}
+
+/// Returns the measured TSC rate in Hz, or `None` if `calibrate` was not
+/// called yet.
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+/// Spins until at least the given duration has passed.
+///
+/// Unlike `task::timer::sleep`, this works with interrupts disabled once the
+/// TSC is calibrated.
+pub fn delay(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+/// Waits until the tick counter moves past `tick` and returns the new value.
+fn wait_for_tick(tick: u64) -> u64 {
+    loop {
+        let now = timer::ticks();
+        if now > tick {
+            return now;
+        }
+        x86_64::instructions::hlt();
+    }
+}
+
+fn read_tsc() -> u64 {
+    // the TSC is available on every x86_64 CPU
+    unsafe { _rdtsc() }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_tsc_is_calibrated() {
+    serial_print!("test_tsc_is_calibrated... ");
+    let frequency = tsc_frequency().expect("TSC not calibrated");
+    assert!(frequency > 1_000_000);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_instant_matches_ticks() {
+    serial_print!("test_instant_matches_ticks... ");
+    let start = Instant::now();
+    let start_tick = timer::ticks();
+    delay(Duration::from_millis(50));
+    let elapsed = start.elapsed();
+    let ticks = timer::ticks() - start_tick;
+    assert!(elapsed >= Duration::from_millis(50));
+    // the TSC and the timer agree within two ticks
+    let tick = Duration::from_nanos(pit::tick_nanos());
+    assert!(tick * (ticks as u32 + 2) >= elapsed);
+    assert!(elapsed + tick * 2 >= tick * ticks as u32);
+    assert!(Instant::now() > start);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_instant_arithmetic() {
+    serial_print!("test_instant_arithmetic... ");
+    let instant = Instant(1_000);
+    assert_eq!(instant + Duration::from_nanos(500), Instant(1_500));
+    assert_eq!(instant - Duration::from_nanos(500), Instant(500));
+    // durations beyond `u64::MAX` nanoseconds are not truncated
+    assert_eq!(instant - Duration::from_secs(u64::max_value()), Instant(0));
+    assert_eq!(Instant(0) + Duration::from_nanos(u64::max_value()), Instant(u64::max_value()));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,152 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use blog_os::allocator;
+use blog_os::memory::{
+    self,
+    address_space::{self, AddressSpace, USER_SPACE_START},
+    vmm, walk, FRAME_ALLOCATOR, MAPPER,
+};
+use blog_os::{serial_print, serial_println};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::memory::bitmap::BitmapFrameAllocator;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_globals(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Creates an address space with `pages` writable pages at `USER_SPACE_START`.
+fn new_space(pages: u64) -> AddressSpace {
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().unwrap();
+    let mut space = AddressSpace::new(frame_allocator).expect("out of frames");
+    if pages > 0 {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let start = VirtAddr::new(USER_SPACE_START);
+        vmm::map_pages(start, pages * 4096, flags, space.mapper(), frame_allocator)
+            .expect("mapping failed");
+    }
+    space
+}
+
+#[test_case]
+fn kernel_mappings_are_shared() {
+    serial_print!("kernel_mappings_are_shared... ");
+    let value = Box::new(42);
+    let space = new_space(0);
+    unsafe { space.activate() };
+    assert!(space.is_active());
+    assert_eq!(*value, 42);
+    assert!(walk::translate(VirtAddr::from_ptr(&*value)).is_some());
+    unsafe { address_space::activate_kernel() };
+    assert!(!space.is_active());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn user_mappings_are_private() {
+    serial_print!("user_mappings_are_private... ");
+    let user_ptr = VirtAddr::new(USER_SPACE_START).as_mut_ptr::<u64>();
+    let a = new_space(1);
+    let b = new_space(1);
+    unsafe {
+        a.activate();
+        user_ptr.write_volatile(1);
+        b.activate();
+        user_ptr.write_volatile(2);
+        a.activate();
+        assert_eq!(user_ptr.read_volatile(), 1);
+        b.activate();
+        assert_eq!(user_ptr.read_volatile(), 2);
+        address_space::activate_kernel();
+    }
+    assert!(walk::translate(VirtAddr::new(USER_SPACE_START)).is_none());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn physical_memory_is_shared() {
+    serial_print!("physical_memory_is_shared... ");
+    let space = new_space(1);
+    // the entry of the space's level 4 table that maps its user page
+    let table = memory::physical_memory_offset() + space.level_4_frame().start_address().as_u64();
+    let entry = unsafe { table.as_ptr::<u64>().add((USER_SPACE_START >> 39) as usize) };
+    unsafe {
+        let value = entry.read_volatile();
+        assert!(value != 0);
+        space.activate();
+        assert_eq!(entry.read_volatile(), value);
+        address_space::activate_kernel();
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn drop_frees_all_frames() {
+    serial_print!("drop_frees_all_frames... ");
+    let free = memory::free_memory();
+    let space = new_space(3);
+    // three pages, one table per level and the level 4 table
+    assert_eq!(memory::free_memory(), free - 7 * 4096);
+    drop(space);
+    assert_eq!(memory::free_memory(), free);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn later_kernel_mappings_are_shared() {
+    serial_print!("later_kernel_mappings_are_shared... ");
+    // the space is created before the region, which is aligned to the range
+    // of a level 4 entry so that no earlier kernel mapping shares its entry
+    let space = new_space(0);
+    let region = {
+        let mut vmm = vmm::VMM.lock();
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let region = vmm
+            .allocate("address space test", Size4KiB::SIZE, 1 << 39)
+            .expect("out of virtual space");
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let (mapper, frame_allocator) = (mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap());
+        vmm::map_pages(region.start, region.size, flags, mapper, frame_allocator)
+            .expect("mapping failed");
+        region
+    };
+    let ptr = region.start.as_mut_ptr::<u64>();
+    unsafe {
+        ptr.write_volatile(42);
+        space.activate();
+        assert_eq!(ptr.read_volatile(), 42);
+        address_space::activate_kernel();
+        vmm::free_mapped(region.start).expect("failed to free the region");
+    }
+    serial_println!("[ok]");
+}
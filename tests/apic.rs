@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::interrupts::{apic, pit};
+use blog_os::task::timer;
+use blog_os::time::{self, Duration};
+use blog_os::{acpi, allocator, memory};
+use blog_os::{serial_print, serial_println};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::memory::bitmap::BitmapFrameAllocator;
+
+    blog_os::init();
+    time::calibrate();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_globals(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn madt_describes_apics() {
+    serial_print!("madt_describes_apics... ");
+    let madt = acpi::madt().expect("no MADT");
+    assert_eq!(madt.local_apic_address, 0xfee0_0000);
+    assert!(!madt.processors.is_empty());
+    assert!(!madt.io_apics.is_empty());
+    // the PIT is connected to GSI 2 on QEMU
+    assert_eq!(madt.isa_irq(0).gsi, 2);
+    assert_eq!(madt.isa_irq(1).gsi, 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn apic_timer_replaces_pit() {
+    serial_print!("apic_timer_replaces_pit... ");
+    apic::init().expect("APIC initialization failed");
+    assert!(apic::is_enabled());
+
+    // the timer keeps ticking at the rate of the PIT, which shows that the
+    // end of each interrupt is signaled to the APIC
+    let start = timer::ticks();
+    time::delay(Duration::from_millis(200));
+    let ticks = timer::ticks() - start;
+    let expected = 200_000_000 / pit::tick_nanos();
+    assert!(ticks + 5 >= expected && ticks <= expected + 5);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,148 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::buddy::{free_map_words, BuddyFrameAllocator, MAX_ORDER};
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::slice;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+static FRAME_ALLOCATOR: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+static PHYS_MEM_OFFSET: Mutex<Option<VirtAddr>> = Mutex::new(None);
+
+/// Set by the last test, which passes if it panics.
+static EXPECT_PANIC: AtomicBool = AtomicBool::new(false);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    *PHYS_MEM_OFFSET.lock() = Some(phys_mem_offset);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if !EXPECT_PANIC.load(Ordering::SeqCst) {
+        blog_os::test_panic_handler(info);
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+fn with_frame_allocator<F: FnOnce(&mut BuddyFrameAllocator)>(f: F) {
+    f(FRAME_ALLOCATOR.lock().as_mut().unwrap())
+}
+
+#[test_case]
+fn allocate_block() {
+    serial_print!("allocate_block... ");
+    with_frame_allocator(|allocator| {
+        let free = allocator.free_frames();
+        let block = allocator.allocate(3).expect("allocation failed");
+        let start = block.start.start_address().as_u64();
+        let end = block.end.start_address().as_u64();
+        assert_eq!(end - start, 8 * 4096);
+        assert_eq!(start % (8 * 4096), 0);
+        assert_eq!(allocator.free_frames(), free - 8);
+        unsafe { allocator.deallocate(block) };
+        assert_eq!(allocator.free_frames(), free);
+    });
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn allocate_aligned_block() {
+    serial_print!("allocate_aligned_block... ");
+    with_frame_allocator(|allocator| {
+        let free = allocator.free_frames();
+        let align = 2 * 1024 * 1024;
+        let block = allocator.allocate_aligned(1, align).expect("allocation failed");
+        let start = block.start.start_address().as_u64();
+        let end = block.end.start_address().as_u64();
+        assert_eq!(end - start, 2 * 4096);
+        assert_eq!(start % align, 0);
+        assert_eq!(allocator.free_frames(), free - 2);
+        unsafe { allocator.deallocate(block) };
+        assert_eq!(allocator.free_frames(), free);
+    });
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn freed_frames_are_merged() {
+    serial_print!("freed_frames_are_merged... ");
+    let phys_mem_offset = PHYS_MEM_OFFSET.lock().unwrap();
+    let (block, map_frame) = {
+        let mut parent = FRAME_ALLOCATOR.lock();
+        let parent = parent.as_mut().unwrap();
+        let block = parent.allocate(MAX_ORDER).expect("allocation failed");
+        let map_frame = parent.allocate(0).expect("allocation failed");
+        (block, map_frame)
+    };
+
+    // manage a single maximal block with a separate allocator, whose free
+    // bitmaps live in a frame of the parent allocator
+    let words = free_map_words(1 << MAX_ORDER);
+    assert!(words * 8 <= 4096);
+    let map_addr = phys_mem_offset + map_frame.start.start_address().as_u64();
+    let free_map = unsafe { slice::from_raw_parts_mut(map_addr.as_mut_ptr::<u64>(), words) };
+    let start = block.start.start_address();
+    let mut allocator =
+        unsafe { BuddyFrameAllocator::new(phys_mem_offset, start, 1 << MAX_ORDER, free_map) };
+    unsafe {
+        allocator.add_region(start, block.end.start_address());
+    }
+    assert_eq!(allocator.free_frames(), 1 << MAX_ORDER);
+
+    // split it into single frames, then free them again
+    for _ in 0..1 << MAX_ORDER {
+        allocator.allocate(0).expect("allocation failed");
+    }
+    assert!(allocator.allocate(0).is_none());
+    for frame in PhysFrame::range(block.start, block.end) {
+        unsafe { allocator.deallocate(PhysFrame::range(frame, frame + 1)) };
+    }
+
+    // all buddies should have been merged back into the original block
+    let merged = allocator.allocate(MAX_ORDER).expect("buddies were not merged");
+    assert_eq!(merged.start, block.start);
+
+    let mut parent = FRAME_ALLOCATOR.lock();
+    let parent = parent.as_mut().unwrap();
+    unsafe {
+        parent.deallocate(block);
+        parent.deallocate(map_frame);
+    }
+    serial_println!("[ok]");
+}
+
+/// Must be the last test, since the test run ends with its panic.
+#[test_case]
+fn double_free_panics() {
+    serial_print!("double_free_panics... ");
+    with_frame_allocator(|allocator| {
+        let block = allocator.allocate(1).expect("allocation failed");
+        unsafe { allocator.deallocate(block) };
+        EXPECT_PANIC.store(true, Ordering::SeqCst);
+        // the first frame is part of the block that was just freed
+        unsafe { allocator.deallocate(PhysFrame::range(block.start, block.start + 1)) };
+    });
+    serial_println!("[double free not detected]");
+    exit_qemu(QemuExitCode::Failed);
+}
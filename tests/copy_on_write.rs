@@ -0,0 +1,117 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::allocator;
+use blog_os::memory::{self, cow, vmm, walk, FRAME_ALLOCATOR, MAPPER};
+use blog_os::{serial_print, serial_println};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::memory::bitmap::BitmapFrameAllocator;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_globals(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Maps a new page, writes `value` to it and shares its frame with a second
+/// page. Returns both pages.
+fn shared_pages(value: u64) -> (VirtAddr, VirtAddr) {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let source = vmm::alloc_mapped("cow source", 4096, flags).expect("mapping failed");
+    unsafe { source.start.as_mut_ptr::<u64>().write_volatile(value) };
+    let alias = vmm::VMM
+        .lock()
+        .allocate("cow alias", 4096, 4096)
+        .expect("out of virtual space");
+
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let (mapper, frame_allocator) = (mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap());
+    let page = Page::containing_address(source.start);
+    let (frame, flags) = cow::share(page, mapper, frame_allocator).expect("share failed");
+    assert!(flags.contains(cow::COPY_ON_WRITE));
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert_eq!(frame_allocator.ref_count(frame), 2);
+    let alias_page = Page::containing_address(alias.start);
+    unsafe { cow::map_shared(alias_page, frame, flags, mapper, frame_allocator) }
+        .expect("map_shared failed");
+    (source.start, alias.start)
+}
+
+fn ref_count(addr: VirtAddr) -> usize {
+    let translation = walk::translate(addr).expect("page not mapped");
+    let frame = PhysFrame::containing_address(translation.phys_addr);
+    FRAME_ALLOCATOR.lock().as_ref().unwrap().ref_count(frame)
+}
+
+fn release(source: VirtAddr, alias: VirtAddr) {
+    unsafe { vmm::free_mapped(source).expect("source was not mapped") };
+    let region = vmm::VMM.lock().free(alias).expect("alias was not allocated");
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let (mapper, frame_allocator) = (mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap());
+    unsafe { vmm::unmap_pages(region.start, region.size, mapper, frame_allocator) };
+}
+
+#[test_case]
+fn write_copies_shared_frame() {
+    serial_print!("write_copies_shared_frame... ");
+    let (source, alias) = shared_pages(42);
+    let source_ptr = source.as_mut_ptr::<u64>();
+    let alias_ptr = alias.as_mut_ptr::<u64>();
+    let phys = walk::translate(source).unwrap().phys_addr;
+    assert_eq!(walk::translate(alias).unwrap().phys_addr, phys);
+    assert_eq!(unsafe { alias_ptr.read_volatile() }, 42);
+
+    // the write faults and gives the alias its own frame
+    unsafe { alias_ptr.write_volatile(43) };
+    assert_eq!(unsafe { alias_ptr.read_volatile() }, 43);
+    assert_eq!(unsafe { source_ptr.read_volatile() }, 42);
+    assert_ne!(walk::translate(alias).unwrap().phys_addr, phys);
+    assert_eq!(ref_count(source), 1);
+    assert_eq!(ref_count(alias), 1);
+
+    // the source holds the last reference, so it is written in place
+    unsafe { source_ptr.write_volatile(44) };
+    assert_eq!(walk::translate(source).unwrap().phys_addr, phys);
+    assert!(walk::translate(source).unwrap().flags.contains(PageTableFlags::WRITABLE));
+    assert_eq!(unsafe { alias_ptr.read_volatile() }, 43);
+
+    release(source, alias);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn unmapping_drops_reference() {
+    serial_print!("unmapping_drops_reference... ");
+    let free = memory::free_memory();
+    let (source, alias) = shared_pages(7);
+    assert_eq!(ref_count(alias), 2);
+    release(source, alias);
+    // page tables that were created for the pages stay allocated
+    assert!(memory::free_memory() + 2 * 4096 >= free);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,216 @@
+#![no_std]
+#![no_main]
+#![feature(llvm_asm)]
+
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// An exception to trigger and the parts that its report must contain.
+struct Case {
+    name: &'static str,
+    trigger: fn(),
+    expected: &'static [&'static str],
+}
+
+/// The exception handlers of the kernel panic with their report, so the
+/// panic handler checks the report and continues with the next case.
+///
+/// Not all exceptions can be raised from ring 0 in long mode: #OF and #BR
+/// come from instructions that are invalid in 64-bit mode, #TS only from task
+/// switches, #AC only from user mode, #MC only from hardware errors, #MF only
+/// with native x87 error reporting, and #VE and #SX only under a hypervisor.
+/// The unit tests of `interrupts::exceptions` cover their reports.
+static CASES: &[Case] = &[
+    Case {
+        name: "divide_error",
+        trigger: divide_error,
+        expected: &["DIVIDE ERROR (#DE, vector 0)"],
+    },
+    Case {
+        name: "invalid_opcode",
+        trigger: invalid_opcode,
+        expected: &["INVALID OPCODE (#UD, vector 6)"],
+    },
+    Case {
+        name: "device_not_available",
+        trigger: device_not_available,
+        expected: &["DEVICE NOT AVAILABLE (#NM, vector 7)"],
+    },
+    Case {
+        name: "general_protection_fault_selector",
+        trigger: general_protection_fault_selector,
+        expected: &[
+            "GENERAL PROTECTION FAULT (#GP, vector 13)",
+            "Error Code: 0x320 (index 100 in the GDT)",
+        ],
+    },
+    Case {
+        name: "general_protection_fault_non_canonical",
+        trigger: general_protection_fault_non_canonical,
+        expected: &["GENERAL PROTECTION FAULT (#GP, vector 13)", "Error Code: 0x0\n"],
+    },
+    Case {
+        name: "stack_segment_fault",
+        trigger: stack_segment_fault,
+        expected: &["STACK-SEGMENT FAULT (#SS, vector 12)", "Error Code: 0x0\n"],
+    },
+    Case {
+        name: "segment_not_present",
+        trigger: segment_not_present,
+        expected: &[
+            "SEGMENT NOT PRESENT (#NP, vector 11)",
+            "Error Code: 0x402 (index 128 in the IDT)",
+        ],
+    },
+    Case {
+        name: "simd_floating_point",
+        trigger: simd_floating_point,
+        expected: &["SIMD FLOATING-POINT EXCEPTION (#XM, vector 19)"],
+    },
+];
+
+/// The index of the case that runs next.
+static NEXT_CASE: AtomicUsize = AtomicUsize::new(0);
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+
+    run_next_case();
+}
+
+fn run_next_case() -> ! {
+    let index = NEXT_CASE.fetch_add(1, Ordering::SeqCst);
+    match CASES.get(index) {
+        Some(case) => {
+            serial_print!("{}... ", case.name);
+            (case.trigger)();
+            panic!("Execution continued after the exception");
+        }
+        None => {
+            exit_qemu(QemuExitCode::Success);
+            loop {}
+        }
+    }
+}
+
+fn divide_error() {
+    unsafe {
+        llvm_asm!("div ecx" :: "{eax}"(1u32), "{edx}"(0u32), "{ecx}"(0u32) : "eax", "edx"
+            : "intel", "volatile");
+    }
+}
+
+fn invalid_opcode() {
+    unsafe { llvm_asm!("ud2" :::: "volatile") };
+}
+
+fn device_not_available() {
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+
+    // x87 instructions fault while the task switched flag is set
+    unsafe {
+        Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED));
+        llvm_asm!("fninit" :::: "volatile");
+    }
+}
+
+fn general_protection_fault_selector() {
+    use x86_64::instructions::segmentation::load_ds;
+    use x86_64::structures::gdt::SegmentSelector;
+    use x86_64::PrivilegeLevel;
+
+    // the GDT has only a few entries
+    unsafe { load_ds(SegmentSelector::new(100, PrivilegeLevel::Ring0)) };
+}
+
+fn general_protection_fault_non_canonical() {
+    let ptr = 0xdead_0000_0000_0000 as *const u64;
+    unsafe { ptr.read_volatile() };
+}
+
+fn stack_segment_fault() {
+    // memory accesses relative to the stack pointer go through the stack
+    // segment, so a non-canonical address causes a #SS instead of a #GP
+    unsafe {
+        llvm_asm!("mov rax, [rsp + rcx]" :: "{rcx}"(0x4000_0000_0000_0000u64) : "rax"
+            : "intel", "volatile");
+    }
+}
+
+fn segment_not_present() {
+    // the IDT has no handler for vector 0x80, so its gate is not present
+    unsafe { llvm_asm!("int 0x80" :::: "intel", "volatile") };
+}
+
+fn simd_floating_point() {
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+
+    // the kernel is built without SSE, so enable it together with its
+    // exceptions (CR4.OSFXSR and CR4.OSXMMEXCPT); the task switched flag may
+    // still be set by `device_not_available`
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        llvm_asm!("mov rax, cr4; or rax, 0x600; mov cr4, rax" ::: "rax" : "intel", "volatile");
+    }
+
+    // the default MXCSR with the divide-by-zero exception unmasked
+    let mxcsr: u32 = 0x1f80 & !(1 << 9);
+    unsafe {
+        llvm_asm!("ldmxcsr [$0]
+                   mov eax, 0x3f800000
+                   movd xmm0, eax
+                   xorps xmm1, xmm1
+                   divss xmm0, xmm1"
+            :: "r"(&mxcsr) : "eax" : "intel", "volatile");
+    }
+}
+
+/// Collects the panic message, cutting it off when the buffer is full.
+struct Buffer {
+    bytes: [u8; 1024],
+    len: usize,
+}
+
+impl Buffer {
+    fn as_str(&self) -> &str {
+        // the message may be cut off in the middle of a character
+        match core::str::from_utf8(&self.bytes[..self.len]) {
+            Ok(s) => s,
+            Err(err) => core::str::from_utf8(&self.bytes[..err.valid_up_to()]).unwrap(),
+        }
+    }
+}
+
+impl Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let case = &CASES[NEXT_CASE.load(Ordering::SeqCst) - 1];
+    let mut message = Buffer {
+        bytes: [0; 1024],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info);
+    if !case.expected.iter().all(|part| message.as_str().contains(part)) {
+        blog_os::test_panic_handler(info);
+    }
+    serial_println!("[ok]");
+
+    // the handlers run with interrupts disabled and never return, so the next
+    // case simply runs on top of the interrupted one
+    run_next_case();
+}
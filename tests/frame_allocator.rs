@@ -0,0 +1,159 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{bitmap::BitmapFrameAllocator, physical::MemorySummary};
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use bootloader::{bootinfo::MemoryMap, entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB, UnusedPhysFrame,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+static MEMORY_MAP: Mutex<Option<&'static MemoryMap>> = Mutex::new(None);
+
+/// Set by the last test, which passes if it panics.
+static EXPECT_PANIC: AtomicBool = AtomicBool::new(false);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    *MEMORY_MAP.lock() = Some(&boot_info.memory_map);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if !EXPECT_PANIC.load(Ordering::SeqCst) {
+        blog_os::test_panic_handler(info);
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+fn with_frame_allocator<F: FnOnce(&mut BitmapFrameAllocator)>(f: F) {
+    f(FRAME_ALLOCATOR.lock().as_mut().unwrap())
+}
+
+#[test_case]
+fn allocate_updates_counts() {
+    serial_print!("allocate_updates_counts... ");
+    with_frame_allocator(|allocator| {
+        let free = allocator.free_frames();
+        let used = allocator.used_frames();
+        let frame: UnusedPhysFrame = allocator.allocate_frame().expect("out of frames");
+        assert_eq!(allocator.free_frames(), free - 1);
+        assert_eq!(allocator.used_frames(), used + 1);
+        allocator.deallocate_frame(frame);
+        assert_eq!(allocator.free_frames(), free);
+        assert_eq!(allocator.used_frames(), used);
+    });
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn frames_are_distinct() {
+    serial_print!("frames_are_distinct... ");
+    with_frame_allocator(|allocator| {
+        let a: UnusedPhysFrame = allocator.allocate_frame().expect("out of frames");
+        let b: UnusedPhysFrame = allocator.allocate_frame().expect("out of frames");
+        assert_ne!(*a, *b);
+        allocator.deallocate_frame(a);
+        allocator.deallocate_frame(b);
+    });
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn freed_frame_is_reused() {
+    serial_print!("freed_frame_is_reused... ");
+    with_frame_allocator(|allocator| {
+        let frame: UnusedPhysFrame = allocator.allocate_frame().expect("out of frames");
+        let addr: PhysFrame = *frame;
+        allocator.deallocate_frame(frame);
+        let frame: UnusedPhysFrame = allocator.allocate_frame().expect("out of frames");
+        assert_eq!(*frame, addr);
+        allocator.deallocate_frame(frame);
+    });
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn allocate_huge_frame() {
+    serial_print!("allocate_huge_frame... ");
+    with_frame_allocator(|allocator| {
+        let free = allocator.free_frames();
+        let frame: UnusedPhysFrame<Size2MiB> = allocator.allocate_frame().expect("no huge frame");
+        assert_eq!(frame.start_address().as_u64() % Size2MiB::SIZE, 0);
+        assert_eq!(allocator.free_frames(), free - 512);
+
+        // none of the small frames inside the huge frame are handed out
+        let small: UnusedPhysFrame = allocator.allocate_frame().expect("out of frames");
+        let huge_start = frame.start_address().as_u64();
+        let small_start = small.start_address().as_u64();
+        assert!(small_start < huge_start || small_start >= huge_start + Size2MiB::SIZE);
+        allocator.deallocate_frame(small);
+
+        allocator.deallocate_frame(frame);
+        assert_eq!(allocator.free_frames(), free);
+    });
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn summary_matches_allocator() {
+    serial_print!("summary_matches_allocator... ");
+    let memory_map = MEMORY_MAP.lock().unwrap();
+    let summary = MemorySummary::from_map(memory_map);
+    assert!(summary.kernel > 0);
+    assert!(summary.usable < summary.total());
+    with_frame_allocator(|allocator| {
+        // the allocator manages the usable memory minus its own bitmap
+        let managed = allocator.usable_frames() as u64 * Size4KiB::SIZE;
+        assert!(managed > 0 && managed <= summary.usable);
+    });
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn allocate_all_frames() {
+    serial_print!("allocate_all_frames... ");
+    with_frame_allocator(|allocator| {
+        let free = allocator.free_frames();
+        let mut count = 0;
+        while FrameAllocator::<Size4KiB>::allocate_frame(allocator).is_some() {
+            count += 1;
+        }
+        assert_eq!(count, free);
+        assert_eq!(allocator.free_frames(), 0);
+    });
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn free_small_frames_as_huge_frame_panics() {
+    serial_print!("free_small_frames_as_huge_frame_panics... ");
+    // all frames are allocated by now, but none of them as a 2 MiB frame
+    let frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(0));
+    with_frame_allocator(|allocator| {
+        EXPECT_PANIC.store(true, Ordering::SeqCst);
+        allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(frame) });
+    });
+    serial_println!("[invalid free not detected]");
+    exit_qemu(QemuExitCode::Failed);
+}
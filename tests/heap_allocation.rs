@@ -9,25 +9,25 @@ extern crate alloc;
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use blog_os::{serial_print, serial_println};
-use blog_os::allocator::HEAP_SIZE;
+use blog_os::allocator::{self, HEAP_SIZE};
 use alloc::boxed::Box;
 use alloc::vec::Vec;
 
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
-    use blog_os::allocator;
-    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use blog_os::memory::{self, bitmap::BitmapFrameAllocator};
     use x86_64::VirtAddr;
 
     blog_os::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
     };
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    memory::init_globals(mapper, frame_allocator);
 
     test_main();
     loop {}
@@ -68,6 +68,8 @@ fn many_boxes() {
     serial_println!("[ok]");
 }
 
+// the bump allocator can't reuse memory while the long-lived box exists
+#[cfg(not(feature = "alloc-bump"))]
 #[test_case]
 fn many_boxes_long_lived() {
     serial_print!("many_boxes_long_lived... ");
@@ -79,3 +81,35 @@ fn many_boxes_long_lived() {
     assert_eq!(*long_lived, 1);
     serial_println!("[ok]");
 }
+
+// only the fixed-size block and slab allocators grow the heap
+#[cfg(any(feature = "alloc-fixed-block", feature = "alloc-slab"))]
+#[test_case]
+fn heap_grows_on_demand() {
+    serial_print!("heap_grows_on_demand... ");
+    let n = 4 * HEAP_SIZE;
+    let mut vec = Vec::with_capacity(n);
+    for i in 0..n {
+        vec.push(i as u8);
+    }
+    assert_eq!(vec[n - 1], (n - 1) as u8);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn stats_track_allocations() {
+    serial_print!("stats_track_allocations... ");
+    let before = allocator::heap_stats();
+    let x = Box::new([0u64; 4]);
+    let during = allocator::heap_stats();
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert!(during.bytes_allocated >= before.bytes_allocated + 32);
+    drop(x);
+    // the debug allocator holds freed allocations back in a quarantine
+    #[cfg(not(feature = "heap-debug"))]
+    {
+        let after = allocator::heap_stats();
+        assert_eq!(after.deallocations, before.deallocations + 1);
+    }
+    serial_println!("[ok]");
+}
@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use blog_os::{allocator, exit_qemu, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::memory::{self, bitmap::BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    double_free();
+    serial_println!("[double free not detected]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+fn double_free() {
+    serial_print!("double_free... ");
+    let layout = Layout::new::<[u64; 4]>();
+    unsafe {
+        let ptr = alloc(layout);
+        dealloc(ptr, layout);
+        dealloc(ptr, layout);
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use blog_os::allocator::{self, HEAP_START};
+use blog_os::memory::guard::{self, GuardPosition};
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::memory::{self, bitmap::BitmapFrameAllocator};
+
+    serial_print!("heap_guard_page... ");
+
+    blog_os::gdt::init();
+    init_test_idt();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    // write to the byte directly below the heap
+    let below_heap = (HEAP_START - 1) as *mut u8;
+    unsafe { below_heap.write_volatile(42) };
+
+    panic!("Execution continued after writing to the guard page");
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: &mut InterruptStackFrame,
+    _error_code: PageFaultErrorCode,
+) {
+    use x86_64::registers::control::Cr2;
+
+    let guard = guard::find(Cr2::read()).expect("fault outside of a guard page");
+    assert_eq!(guard.owner, "kernel heap");
+    assert_eq!(guard.position, GuardPosition::Below);
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
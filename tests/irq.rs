@@ -0,0 +1,98 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::interrupts::irq::{self, IrqError};
+use blog_os::task::timer;
+use blog_os::time::{self, Duration};
+use blog_os::{serial_print, serial_println};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    blog_os::init();
+    time::calibrate();
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+static CALLS: AtomicU64 = AtomicU64::new(0);
+
+fn count_call() {
+    CALLS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test_case]
+fn shared_timer_line() {
+    serial_print!("shared_timer_line... ");
+    irq::register(irq::TIMER, count_call).expect("failed to register the handler");
+    assert_eq!(
+        irq::register(irq::TIMER, count_call),
+        Err(IrqError::AlreadyRegistered(irq::TIMER))
+    );
+
+    // the handler of the timer module still runs next to the new one
+    let start_calls = CALLS.load(Ordering::Relaxed);
+    let start_ticks = timer::ticks();
+    time::delay(Duration::from_millis(100));
+    let calls = CALLS.load(Ordering::Relaxed) - start_calls;
+    let ticks = timer::ticks() - start_ticks;
+    assert!(calls > 0);
+    assert!(calls + 1 >= ticks && calls <= ticks + 1);
+
+    irq::unregister(irq::TIMER, count_call).expect("failed to unregister the handler");
+    let calls = CALLS.load(Ordering::Relaxed);
+    let start_ticks = timer::ticks();
+    time::delay(Duration::from_millis(50));
+    assert!(timer::ticks() > start_ticks);
+    assert_eq!(CALLS.load(Ordering::Relaxed), calls);
+    assert!(irq::has_handlers(irq::TIMER));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn full_line() {
+    serial_print!("full_line... ");
+    // the bodies differ so that the handlers aren't merged into one function
+    fn first() {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+    fn second() {
+        CALLS.fetch_add(2, Ordering::Relaxed);
+    }
+    fn third() {
+        CALLS.fetch_add(3, Ordering::Relaxed);
+    }
+    fn fourth() {
+        CALLS.fetch_add(4, Ordering::Relaxed);
+    }
+    fn fifth() {
+        CALLS.fetch_add(5, Ordering::Relaxed);
+    }
+
+    // IRQ 5 is not used by the kernel
+    let handlers: [irq::IrqHandler; irq::MAX_SHARED_HANDLERS] = [first, second, third, fourth];
+    for &handler in handlers.iter() {
+        irq::register(5, handler).expect("failed to register the handler");
+    }
+    assert_eq!(irq::register(5, fifth), Err(IrqError::LineFull(5)));
+
+    // a freed slot can be reused
+    irq::unregister(5, second).expect("failed to unregister the handler");
+    irq::register(5, fifth).expect("failed to register the handler");
+    let handlers: [irq::IrqHandler; irq::MAX_SHARED_HANDLERS] = [first, third, fourth, fifth];
+    for &handler in handlers.iter() {
+        irq::unregister(5, handler).expect("failed to unregister the handler");
+    }
+    assert!(!irq::has_handlers(5));
+    serial_println!("[ok]");
+}
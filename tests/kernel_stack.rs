@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::allocator;
+use blog_os::memory::{self, guard, stack::StackBounds, walk};
+use blog_os::{serial_print, serial_println};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::memory::bitmap::BitmapFrameAllocator;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_globals(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn stack_is_mapped_below_guard() {
+    serial_print!("stack_is_mapped_below_guard... ");
+    let stack = StackBounds::alloc("test stack", 4 * 4096).expect("stack allocation failed");
+    assert_eq!(stack.size(), 4 * 4096);
+    assert_eq!(stack.start(), stack.guard_start() + 4096u64);
+
+    // both ends of the stack are usable
+    unsafe {
+        stack.start().as_mut_ptr::<u8>().write_volatile(1);
+        (stack.end() - 1u64).as_mut_ptr::<u8>().write_volatile(2);
+    }
+    let flags = walk::translate(stack.start()).expect("stack not mapped").flags;
+    assert!(flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+
+    assert!(walk::translate(stack.guard_start()).is_none());
+    let guard = guard::find(stack.start() - 1u64).expect("guard page not registered");
+    assert_eq!(guard.owner, "test stack");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn drop_frees_stack() {
+    serial_print!("drop_frees_stack... ");
+    let free = memory::free_memory();
+    let stack = StackBounds::alloc("test stack", 8 * 4096).expect("stack allocation failed");
+    let (start, guard_start) = (stack.start(), stack.guard_start());
+    assert!(memory::free_memory() <= free - 8 * 4096);
+
+    drop(stack);
+    assert!(walk::translate(start).is_none());
+    assert!(guard::find(guard_start).is_none());
+    // page tables that were created for the stack stay allocated
+    assert!(memory::free_memory() + 3 * 4096 >= free);
+    serial_println!("[ok]");
+}
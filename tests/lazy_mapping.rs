@@ -0,0 +1,86 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{lazy, FRAME_ALLOCATOR};
+use blog_os::{allocator, serial_print, serial_println};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::PageTableFlags;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::memory::{self, bitmap::BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_globals(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    FRAME_ALLOCATOR.lock().as_ref().unwrap().free_frames()
+}
+
+#[test_case]
+fn pages_are_mapped_on_first_touch() {
+    serial_print!("pages_are_mapped_on_first_touch... ");
+    let size = 64 * 1024 * 1024;
+    let free_before = free_frames();
+    let region = lazy::reserve_lazy("lazy test", size, PageTableFlags::WRITABLE)
+        .expect("reservation failed");
+    assert_eq!(free_frames(), free_before);
+
+    // touch the first and the last page of the region
+    let first: *mut u64 = region.start.as_mut_ptr();
+    let last: *mut u64 = (region.end() - 8u64).as_mut_ptr();
+    unsafe {
+        assert_eq!(first.read_volatile(), 0);
+        first.write_volatile(42);
+        last.write_volatile(43);
+        assert_eq!(first.read_volatile(), 42);
+        assert_eq!(last.read_volatile(), 43);
+    }
+    // two frames plus possibly a few page tables
+    let used = free_before - free_frames();
+    assert!(used >= 2 && used < 8);
+
+    unsafe { lazy::free_lazy(region.start).expect("free failed") };
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn freeing_returns_touched_frames() {
+    serial_print!("freeing_returns_touched_frames... ");
+    let region = lazy::reserve_lazy("lazy test", 16 * 4096, PageTableFlags::WRITABLE)
+        .expect("reservation failed");
+    // touch one page first so that page tables for the region exist
+    unsafe { region.start.as_mut_ptr::<u8>().write_volatile(1) };
+    let free_before = free_frames();
+    for page in 1..16u64 {
+        let ptr: *mut u8 = (region.start + page * 4096).as_mut_ptr();
+        unsafe { ptr.write_volatile(1) };
+    }
+    assert_eq!(free_frames(), free_before - 15);
+
+    unsafe { lazy::free_lazy(region.start).expect("free failed") };
+    assert_eq!(free_frames(), free_before + 1);
+    serial_println!("[ok]");
+}
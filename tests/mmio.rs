@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::mmio::{CacheMode, MmioRegion};
+use blog_os::memory::vmm::VMM;
+use blog_os::{allocator, serial_print, serial_println};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::PhysAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::memory::{self, bitmap::BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_globals(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// The physical address of the VGA text buffer.
+const VGA_BUFFER: u64 = 0xb8000;
+
+#[test_case]
+fn map_vga_buffer() {
+    serial_print!("map_vga_buffer... ");
+    let region = unsafe { MmioRegion::map(PhysAddr::new(VGA_BUFFER), 4000, CacheMode::Uncached) }
+        .expect("mapping failed");
+    // the last character of the screen
+    let offset = 3998;
+    region.write::<u16>(offset, 0x0f21);
+    assert_eq!(region.read::<u16>(offset), 0x0f21);
+
+    // the same memory is visible through the physical memory mapping
+    let phys_ptr = (blog_os::memory::physical_memory_offset() + VGA_BUFFER + offset).as_ptr::<u16>();
+    assert_eq!(unsafe { phys_ptr.read_volatile() }, 0x0f21);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn unaligned_region_keeps_offset() {
+    serial_print!("unaligned_region_keeps_offset... ");
+    let phys_addr = PhysAddr::new(VGA_BUFFER + 0x10);
+    let region = unsafe { MmioRegion::map(phys_addr, 0x20, CacheMode::Uncached) }
+        .expect("mapping failed");
+    assert_eq!(region.base().as_u64() % 4096, 0x10);
+    assert_eq!(region.phys_addr(), phys_addr);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn drop_frees_virtual_range() {
+    serial_print!("drop_frees_virtual_range... ");
+    let region = unsafe { MmioRegion::map(PhysAddr::new(VGA_BUFFER), 4000, CacheMode::Uncached) }
+        .expect("mapping failed");
+    let base = region.base();
+    assert!(VMM.lock().find(base).is_some());
+    drop(region);
+    assert!(VMM.lock().find(base).is_none());
+    serial_println!("[ok]");
+}
@@ -0,0 +1,121 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::allocator::{self, HEAP_SIZE, HEAP_START};
+use blog_os::memory::{
+    self,
+    vmm,
+    walk::{self, MappingSize},
+    FRAME_ALLOCATOR, MAPPER,
+};
+use blog_os::{serial_print, serial_println};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{
+    mapper::MapperAllSizes, PageSize, PageTableFlags, Size2MiB, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::memory::bitmap::BitmapFrameAllocator;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_globals(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn translate_matches_mapper() {
+    serial_print!("translate_matches_mapper... ");
+    let addresses = [
+        VirtAddr::new(HEAP_START as u64 + 42),
+        memory::physical_memory_offset() + 0x1234u64,
+        VirtAddr::from_ptr(&translate_matches_mapper as *const _),
+    ];
+    let mapper = MAPPER.lock();
+    let mapper = mapper.as_ref().unwrap();
+    for &addr in addresses.iter() {
+        let translation = walk::translate(addr).expect("address not mapped");
+        assert_eq!(Some(translation.phys_addr), mapper.translate_addr(addr));
+    }
+    let phys = walk::translate(memory::physical_memory_offset() + 0x1234u64).unwrap();
+    assert_eq!(phys.phys_addr, PhysAddr::new(0x1234));
+    assert!(walk::translate(VirtAddr::new(HEAP_START as u64 - 1)).is_none());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn walk_merges_heap_pages() {
+    serial_print!("walk_merges_heap_pages... ");
+    let heap_start = VirtAddr::new(HEAP_START as u64);
+    let heap_end = heap_start + HEAP_SIZE;
+    let mut heap_ranges = 0;
+    let mut covered_end = heap_start;
+    let mut previous_end = 0;
+    walk::walk(|range| {
+        assert!(range.start.as_u64() >= previous_end, "ranges are not sorted");
+        previous_end = range.start.as_u64() + (range.size - 1);
+        let range_end = range.start + range.size;
+        if range.start < heap_end && heap_start < range_end {
+            heap_ranges += 1;
+            assert!(range.flags.contains(PageTableFlags::WRITABLE));
+            assert!(range.start <= covered_end, "heap page is not mapped");
+            covered_end = range_end;
+        }
+    });
+    // the page tables of the heap are allocated right after the frame of its
+    // first page, so that page may be a range of its own, but all others must
+    // be merged
+    assert!(covered_end >= heap_end, "heap page is not mapped");
+    assert!(heap_ranges <= 2, "heap pages were not merged");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn map_range_prefers_huge_pages() {
+    serial_print!("map_range_prefers_huge_pages... ");
+    let size = Size2MiB::SIZE + Size4KiB::SIZE;
+    let region = vmm::VMM
+        .lock()
+        .allocate("huge test", size, Size2MiB::SIZE)
+        .expect("out of virtual space");
+    {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let (mapper, frame_allocator) = (mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap());
+        let free = frame_allocator.free_frames();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        vmm::map_range(region.start, size, flags, mapper, frame_allocator).expect("map failed");
+
+        let huge = walk::translate(region.start + 42u64).expect("huge page not mapped");
+        assert_eq!(huge.page_size, MappingSize::Size2MiB);
+        let small = walk::translate(region.start + Size2MiB::SIZE).expect("page not mapped");
+        assert_eq!(small.page_size, MappingSize::Size4KiB);
+
+        unsafe { vmm::unmap_range(region.start, size, mapper, frame_allocator) };
+        assert!(walk::translate(region.start).is_none());
+        // page tables that were created for the range stay allocated
+        assert!(frame_allocator.free_frames() + 2 >= free);
+    }
+    vmm::VMM.lock().free(region.start).unwrap();
+    serial_println!("[ok]");
+}
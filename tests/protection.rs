@@ -0,0 +1,75 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::allocator::{self, HEAP_START};
+use blog_os::memory::{self, protect, walk};
+use blog_os::{serial_print, serial_println};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+static DATA: u64 = 42;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::memory::bitmap::BitmapFrameAllocator;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    unsafe { protect::protect_kernel(&boot_info.memory_map, &mut mapper) }
+        .expect("failed to protect the kernel mappings");
+    memory::init_globals(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn flags(addr: VirtAddr) -> PageTableFlags {
+    walk::translate(addr).expect("address not mapped").flags
+}
+
+#[test_case]
+fn protection_is_enabled() {
+    serial_print!("protection_is_enabled... ");
+    assert!(protect::is_enabled());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn text_is_read_only() {
+    serial_print!("text_is_read_only... ");
+    let text = flags(VirtAddr::new(text_is_read_only as usize as u64));
+    assert!(!text.contains(PageTableFlags::WRITABLE));
+    assert!(!text.contains(PageTableFlags::NO_EXECUTE));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn data_is_not_executable() {
+    serial_print!("data_is_not_executable... ");
+    let addresses = [
+        VirtAddr::new(HEAP_START as u64),
+        VirtAddr::from_ptr(&DATA),
+        memory::physical_memory_offset(),
+    ];
+    for &addr in addresses.iter() {
+        assert!(flags(addr).contains(PageTableFlags::NO_EXECUTE));
+    }
+    serial_println!("[ok]");
+}
@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+#![feature(wake_trait)]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, task::Wake};
+use blog_os::allocator;
+use blog_os::task::{executor::Executor, timer, Task};
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use futures_util::future::pending;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::memory::{self, bitmap::BitmapFrameAllocator};
+
+    serial_print!("timer... ");
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(run_tests()));
+    executor.run();
+}
+
+async fn run_tests() {
+    // sleeping waits for at least the requested number of ticks
+    let start = timer::ticks();
+    timer::sleep(Duration::from_millis(200)).await;
+    assert!(timer::ticks() - start >= timer::duration_to_ticks(Duration::from_millis(200)));
+
+    // a future that completes in time is not interrupted
+    let result = timer::timeout(Duration::from_millis(100), async { 42 }).await;
+    assert_eq!(result, Ok(42));
+
+    // a future that never completes times out
+    let start = timer::ticks();
+    let result = timer::timeout(Duration::from_millis(100), pending::<()>()).await;
+    assert_eq!(result, Err(timer::Elapsed));
+    assert!(timer::ticks() > start);
+
+    sleep_wakes_without_executor();
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// Sets a flag when it is woken.
+struct FlagWaker(AtomicBool);
+
+impl Wake for FlagWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Checks that the timer interrupt wakes a sleep that is polled outside of the
+/// executor, which never calls `timer::wake_expired` for it.
+fn sleep_wakes_without_executor() {
+    let flag = Arc::new(FlagWaker(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut context = Context::from_waker(&waker);
+    let mut sleep = timer::sleep(Duration::from_millis(50));
+    assert_eq!(Pin::new(&mut sleep).poll(&mut context), Poll::Pending);
+    while !flag.0.load(Ordering::SeqCst) {
+        x86_64::instructions::hlt();
+    }
+    assert!(timer::ticks() >= sleep.deadline());
+    assert_eq!(Pin::new(&mut sleep).poll(&mut context), Poll::Ready(()));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}